@@ -2,9 +2,22 @@
 #include <stddef.h>
 #include <stdlib.h>
 #include <string.h>
+
+// The input is written by the (uninstrumented) fuzzer, MSan would see it as uninitialized
+#if defined(__has_feature)
+#if __has_feature(memory_sanitizer)
+#include <sanitizer/msan_interface.h>
+#define UNPOISON_INPUT(data, size) __msan_unpoison(data, size)
+#endif
+#endif
+#ifndef UNPOISON_INPUT
+#define UNPOISON_INPUT(data, size)
+#endif
+
 void check_buf(char *buf, size_t buf_len);
 
 extern "C" int LLVMFuzzerTestOneInput(const uint8_t *data, size_t size) {
+  UNPOISON_INPUT(data, size);
   check_buf((char*) data, size);
   return 0;
 }
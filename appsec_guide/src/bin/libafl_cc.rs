@@ -1,6 +1,6 @@
 use std::env;
 
-use libafl_cc::{ClangWrapper, CompilerWrapper, Configuration, ToolWrapper};
+use libafl_cc::{ClangWrapper, CompilerWrapper, Configuration, LLVMPasses, ToolWrapper};
 
 pub fn main() {
     let args: Vec<String> = env::args().collect();
@@ -17,18 +17,43 @@ pub fn main() {
         dir.pop();
 
         let mut cc = ClangWrapper::new();
-        if let Some(code) = cc
-            .cpp(is_cpp)
+        cc.cpp(is_cpp)
             // silence the compiler wrapper output, needed for some configure scripts.
             .silence(true)
             .parse_args(&args)
             .expect("Failed to parse the command line")
             .link_staticlib(&dir, "appsec_guide")
-            .add_args(&Configuration::GenerateCoverageMap.to_flags().unwrap())
-            //.add_args(&Configuration::AddressSanitizer.to_flags().unwrap())
-            .run()
-            .expect("Failed to run the wrapped compiler")
-        {
+            .add_args(&Configuration::GenerateCoverageMap.to_flags().unwrap());
+
+        // Sanitizer of this build, its runtime options are set by the fuzzer (see lab_common::sanitizers)
+        match env::var("LIBAFL_SANITIZER").as_deref().unwrap_or("none") {
+            "address" => {
+                cc.add_args(&Configuration::AddressSanitizer.to_flags().unwrap());
+            }
+            "undefined" => {
+                cc.add_arg("-fsanitize=undefined");
+            }
+            "memory" => {
+                cc.add_arg("-fsanitize=memory")
+                    .add_arg("-fsanitize-memory-track-origins");
+            }
+            "none" => {}
+            other => panic!(
+                "Unknown LIBAFL_SANITIZER {other:?}, expected address, undefined, memory or none"
+            ),
+        }
+
+        // Embed the constants of compared operands, read back by `autotokens()` at startup
+        if env::var("LIBAFL_AUTOTOKENS").is_ok_and(|val| val == "1") {
+            cc.add_pass(LLVMPasses::AutoTokens);
+        }
+
+        // The forkserver build exports its symbols, for the frames of a crashing child (`dladdr`)
+        if cfg!(feature = "forkserver") {
+            cc.add_link_arg("-rdynamic");
+        }
+
+        if let Some(code) = cc.run().expect("Failed to run the wrapped compiler") {
             std::process::exit(code);
         }
     } else {
@@ -0,0 +1,146 @@
+//! Time series of the stats (`--history FILE`), to plot coverage over time and compare campaigns.
+//!
+//! [`HistoryMonitor`] wraps another monitor, every [`HISTORY_INTERVAL`] it appends a CSV line
+//! per client and one for all of them (`client` is `all`): the total executions, corpus and
+//! objectives, and the edges aggregated the way the monitor shows them.
+use core::time::Duration;
+use std::{
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use libafl::monitors::{Aggregator, ClientStats, Monitor, UserStats, UserStatsValue};
+use libafl_bolts::{current_time, ClientId};
+
+/// How often the records are appended
+const HISTORY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// First line of the file
+const HEADER: &str =
+    "unix_time,run_time,client,executions,execs_per_sec,corpus,objectives,edges,coverage";
+
+/// Appends the stats to a CSV file, then displays them with the wrapped monitor
+#[derive(Debug, Clone)]
+pub struct HistoryMonitor<M> {
+    base: M,
+    path: Option<PathBuf>,
+    last_write: Duration,
+    /// The user stats of all clients, for the `all` record
+    aggregator: Aggregator,
+}
+
+impl<M> HistoryMonitor<M>
+where
+    M: Monitor,
+{
+    /// Only the wrapped monitor without a `path`
+    pub fn new(path: Option<PathBuf>, base: M) -> Self {
+        Self {
+            base,
+            path,
+            last_write: Duration::ZERO,
+            aggregator: Aggregator::new(),
+        }
+    }
+
+    fn write_records(&mut self, now: Duration) -> io::Result<()> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        let unix_time = now.as_secs();
+        let run_time = now.saturating_sub(self.base.start_time()).as_secs();
+
+        let mut records = String::new();
+        for (id, client) in self.base.client_stats_mut().iter_mut().enumerate() {
+            if !client.enabled {
+                continue;
+            }
+            let edges = edges(client.user_monitor.get("edges").map(UserStats::value));
+            let execs_per_sec = client.execs_per_sec(now);
+            writeln!(
+                records,
+                "{unix_time},{run_time},{id},{},{execs_per_sec:.2},{},{},{}",
+                client.executions,
+                client.corpus_size,
+                client.objective_size,
+                coverage_columns(edges),
+            )
+            .unwrap();
+        }
+        let execs_per_sec = self.base.execs_per_sec();
+        writeln!(
+            records,
+            "{unix_time},{run_time},all,{},{execs_per_sec:.2},{},{},{}",
+            self.base.total_execs(),
+            self.base.corpus_size(),
+            self.base.objective_size(),
+            coverage_columns(edges(self.aggregator.aggregated.get("edges"))),
+        )
+        .unwrap();
+
+        let new_file = !path.exists();
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if new_file {
+            writeln!(file, "{HEADER}")?;
+        }
+        file.write_all(records.as_bytes())
+    }
+}
+
+/// Covered and total edges, from the stats of the `edges` map feedback
+fn edges(value: Option<&UserStatsValue>) -> Option<(u64, u64)> {
+    match value? {
+        UserStatsValue::Ratio(covered, total) => Some((*covered, *total)),
+        _ => None,
+    }
+}
+
+/// The `edges,coverage` columns, empty when unknown
+fn coverage_columns(edges: Option<(u64, u64)>) -> String {
+    match edges {
+        Some((covered, total)) if total > 0 => {
+            format!("{covered},{:.2}", covered as f64 * 100.0 / total as f64)
+        }
+        Some((covered, _)) => format!("{covered},"),
+        None => ",".to_string(),
+    }
+}
+
+impl<M> Monitor for HistoryMonitor<M>
+where
+    M: Monitor,
+{
+    fn client_stats_mut(&mut self) -> &mut Vec<ClientStats> {
+        self.base.client_stats_mut()
+    }
+
+    fn client_stats(&self) -> &[ClientStats] {
+        self.base.client_stats()
+    }
+
+    fn start_time(&self) -> Duration {
+        self.base.start_time()
+    }
+
+    fn set_start_time(&mut self, time: Duration) {
+        self.base.set_start_time(time);
+    }
+
+    fn aggregate(&mut self, name: &str) {
+        self.aggregator.aggregate(name, self.base.client_stats());
+        self.base.aggregate(name);
+    }
+
+    fn display(&mut self, event_msg: &str, sender_id: ClientId) {
+        let now = current_time();
+        if now.saturating_sub(self.last_write) >= HISTORY_INTERVAL {
+            self.last_write = now;
+            if let Err(err) = self.write_records(now) {
+                eprintln!("Failed to append to the history: {err}");
+            }
+        }
+        self.base.display(event_msg, sender_id);
+    }
+}
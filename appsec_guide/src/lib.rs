@@ -1,47 +1,159 @@
 use core::time::Duration;
-use std::{env, net::SocketAddr, path::PathBuf};
+use std::{
+    env,
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    path::PathBuf,
+    process,
+};
+
+use clap::{error::ErrorKind, CommandFactory, Parser};
 
 use libafl::{
-    corpus::{Corpus, InMemoryCorpus, OnDiskCorpus},
-    events::{launcher::Launcher, EventConfig},
-    executors::{inprocess::InProcessExecutor, ExitKind},
-    feedback_or, feedback_or_fast,
-    feedbacks::{CrashFeedback, MaxMapFeedback, TimeFeedback, TimeoutFeedback},
+    corpus::{Corpus, InMemoryOnDiskCorpus, OnDiskCorpus},
+    events::{broker_hooks::StdLlmpEventHook, launcher::Launcher, EventConfig},
+    executors::{forkserver::ForkserverExecutor, inprocess::InProcessExecutor, ExitKind},
+    feedback_or,
+    feedbacks::{CrashFeedback, MaxMapFeedback, TimeFeedback},
     fuzzer::{Fuzzer, StdFuzzer},
     inputs::{BytesInput, HasTargetBytes},
-    monitors::{MultiMonitor, OnDiskTOMLMonitor},
+    monitors::{Monitor, OnDiskTOMLMonitor},
     mutators::{
-        scheduled::{havoc_mutations, StdScheduledMutator},
+        scheduled::{havoc_mutations, tokens_mutations, StdScheduledMutator},
+        token_mutations::Tokens,
     },
-    observers::{CanTrack, HitcountsMapObserver, TimeObserver},
+    observers::{CanTrack, HitcountsMapObserver, StdMapObserver, TimeObserver},
     schedulers::{IndexesLenTimeMinimizerScheduler, QueueScheduler},
     stages::mutational::StdMutationalStage,
     state::{HasCorpus, StdState},
-    Error,
+    Error, HasMetadata,
 };
 use libafl_bolts::{
-    core_affinity::Cores,
+    core_affinity::{CoreId, Cores},
+    llmp::LlmpBroker,
     rands::StdRand,
-    shmem::{ShMemProvider, StdShMemProvider},
-    tuples::{tuple_list},
-    AsSlice,
+    shmem::{ShMemProvider, StdShMemProvider, UnixShMemProvider},
+    tuples::{tuple_list, Merge},
+    AsSlice, AsSliceMut,
 };
+#[cfg(any(target_os = "linux", target_vendor = "apple"))]
+use libafl_targets::autotokens;
 use libafl_targets::{libfuzzer_initialize, libfuzzer_test_one_input, std_edges_map_observer};
 
+use lab_common::{
+    afl_output::{AflCorpus, AflNameFeedback, AflOutput, AflStatsStage},
+    cmin::EdgesObserver,
+    forkserver,
+    hangs::{HangFeedback, HangStage},
+    monitor::FuzzerMonitor,
+    replay,
+    resume::{self, SaveStateStage},
+    seed::{self, SeedMetadata},
+    sync::{SyncOriginFeedback, SyncStage},
+};
+
+mod history;
+mod options;
+mod triage;
+use history::HistoryMonitor;
+use options::{FuzzerOptions, Role};
+
+/// The edges map of the harness, for the modes that only run inputs
+#[cfg(not(test))]
+fn edges_observer() -> EdgesObserver {
+    HitcountsMapObserver::new(unsafe { std_edges_map_observer("edges") })
+}
 
 /// The main fn, `no_mangle` as it is a C symbol
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn libafl_main() {
+    // Started by the ForkserverExecutor of a `--forkserver` client
+    if env::var_os(forkserver::TARGET_ENV).is_some() {
+        forkserver::run_target();
+    }
+
+    let options = FuzzerOptions::parse();
+    if options.bind.is_some() && options.role != Role::Broker {
+        FuzzerOptions::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--bind is the address of the broker of --role broker, the brokers of the other roles only serve their own clients",
+            )
+            .exit();
+    }
+    if options.forkserver {
+        forkserver::check_build().expect("Invalid fuzzer configuration");
+    }
+    let timeout = Duration::from_millis(options.timeout);
+    let hang_timeout = options
+        .hang_timeout
+        .map_or(timeout * 2, Duration::from_millis);
 
     println!(
         "Workdir: {:?}",
         env::current_dir().unwrap().to_string_lossy().to_string()
     );
 
-    let mut run_client = |state: Option<_>, mut restarting_mgr, _core_id| {
+    // Only run the given inputs, exit non-zero on any crash or timeout
+    if options.replay {
+        let clean = replay::replay(&options.corpus_dirs, timeout, None, edges_observer)
+            .expect("An error occurred while replaying the inputs");
+        if !clean {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // Only cluster the given crashes by crash line, with their exploitability
+    if let Some(output_dir) = &options.triage {
+        triage::triage(&options.corpus_dirs, output_dir, timeout)
+            .expect("An error occurred while triaging the crashes");
+        return;
+    }
+
+    let cores = Cores::from_cmdline(&options.cores).expect("Invalid --cores");
+
+    // The same for all clients, they derive their own seed from it
+    let seed = seed::campaign_seed(options.seed);
+    println!("Seed: {seed} (repeat the campaign with --seed {seed})");
+
+    let mut run_client = |state: Option<_>, mut restarting_mgr, core_id: CoreId| {
+        // With `--afl-out` every client writes its own AFL++ instance directory
+        let afl_output = options
+            .afl_out
+            .as_deref()
+            .map(|out| AflOutput::create(out, &format!("core{}", core_id.0), "appsec_guide"))
+            .transpose()?;
+        let (queue_dir, crashes_dir, hangs_dir) = match &afl_output {
+            Some(output) => (output.queue(), output.crashes(), output.hangs()),
+            None => (
+                client_queue_dir(&options, core_id),
+                PathBuf::from("./crashes"),
+                options.hangs.clone(),
+            ),
+        };
+        let state_path = resume::state_path(&queue_dir);
+
+        // With `--forkserver` the coverage map is shared with the target process
+        let mut forkserver_shmem_provider = UnixShMemProvider::new()?;
+        let mut coverage_shmem = if options.forkserver {
+            Some(forkserver::coverage_map(&mut forkserver_shmem_provider)?)
+        } else {
+            None
+        };
+
         // Create an observation channel using the coverage map
-        let edges_observer =
-            HitcountsMapObserver::new(unsafe { std_edges_map_observer("edges") }).track_indices();
+        let edges_observer = HitcountsMapObserver::new(unsafe {
+            match &mut coverage_shmem {
+                Some(shmem) => StdMapObserver::from_mut_ptr(
+                    "edges",
+                    shmem.as_slice_mut().as_mut_ptr(),
+                    forkserver::MAP_SIZE,
+                ),
+                None => std_edges_map_observer("edges"),
+            }
+        })
+        .track_indices();
 
         // Create an observation channel to keep track of the execution time
         let time_observer = TimeObserver::new("time");
@@ -52,36 +164,68 @@ pub extern "C" fn libafl_main() {
             // New maximization map feedback linked to the edges observer
             MaxMapFeedback::new(&edges_observer),
             // Time feedback
-            TimeFeedback::new(&time_observer)
+            TimeFeedback::new(&time_observer),
+            // AFL++ names with `--afl-out`
+            AflNameFeedback::new(AflCorpus::Queue, afl_output.is_some()),
+            // The origin of the inputs of `--sync-dir`
+            SyncOriginFeedback::new()
         );
 
-        // A feedback to choose if an input is a solution or not
-        let mut objective = feedback_or_fast!(CrashFeedback::new(), TimeoutFeedback::new());
-
-        // If not restarting, create a State from scratch
-        let mut state = state.unwrap_or_else(|| {
-            StdState::new(
-                // RNG
-                StdRand::new(),
-                // Corpus that will be evolved, we keep it in memory for performance
-                InMemoryCorpus::new(),
-                // Corpus in which we store solutions (crashes in this example),
-                // on disk so the user can get them after stopping the fuzzer
-                OnDiskCorpus::new(PathBuf::from("./crashes")).unwrap(),
-                // States of the feedbacks.
-                // The feedbacks can report the data that should persist in the State.
-                &mut feedback,
-                // Same for objective feedbacks
-                &mut objective,
-            )
-            .unwrap()
-        });
+        // A feedback to choose if an input is a solution or not,
+        // the timeouts are noted for the hang stage, which runs them again
+        let mut objective = feedback_or!(
+            CrashFeedback::new(),
+            HangFeedback::new(&time_observer, timeout),
+            AflNameFeedback::new(AflCorpus::Crashes, afl_output.is_some()),
+            SyncOriginFeedback::new()
+        );
+
+        // If not restarting, create a State from scratch, with `--resume` filled with the queue
+        // and the state saved by the previous run
+        let client_seed = seed::client_seed(seed, core_id);
+        let mut state = match state {
+            Some(state) => state,
+            None => {
+                let mut state = StdState::new(
+                    // RNG
+                    StdRand::with_seed(client_seed),
+                    // Corpus that will be evolved, in memory for performance and on disk
+                    // so the fuzzer can resume from it
+                    InMemoryOnDiskCorpus::new(&queue_dir)?,
+                    // Corpus in which we store solutions (crashes in this example),
+                    // on disk so the user can get them after stopping the fuzzer
+                    OnDiskCorpus::new(crashes_dir)?,
+                    // States of the feedbacks.
+                    // The feedbacks can report the data that should persist in the State.
+                    &mut feedback,
+                    // Same for objective feedbacks
+                    &mut objective,
+                )?;
+                if options.resume {
+                    if resume::load_state(&state_path, &mut state)? {
+                        println!("Resuming from {}", state_path.display());
+                    } else {
+                        println!("No saved state in {}, starting over", state_path.display());
+                    }
+                }
+                state
+            }
+        };
 
         println!("We're a client, let's fuzz :)");
 
-        // Setup a basic mutator with a mutational stage
-        let mutator = StdScheduledMutator::new(havoc_mutations());
-        let mut stages = tuple_list!(StdMutationalStage::new(mutator));
+        // A restarted client goes on with the RNG of its saved state
+        if !state.has_metadata::<SeedMetadata>() {
+            state.add_metadata(SeedMetadata { seed, client_seed });
+        }
+        let seeds = state.metadata::<SeedMetadata>()?;
+        println!("Seed: {}, client seed: {}", seeds.seed, seeds.client_seed);
+
+        // Tokens the `LIBAFL_AUTOTOKENS=1` build embedded in the target binary
+        #[cfg(any(target_os = "linux", target_vendor = "apple"))]
+        if state.metadata_map().get::<Tokens>().is_none() {
+            state.add_metadata(autotokens()?);
+        }
 
         // A minimization+queue policy to get testcasess from the corpus
         let scheduler =
@@ -98,15 +242,6 @@ pub extern "C" fn libafl_main() {
             ExitKind::Ok
         };
 
-        let mut executor = InProcessExecutor::with_timeout(
-            &mut harness,
-            tuple_list!(edges_observer, time_observer),
-            &mut fuzzer,
-            &mut state,
-            &mut restarting_mgr,
-            Duration::new(10, 0),
-        )?;
-
         // The actual target run starts here.
         // Call LLVMFUzzerInitialize() if present.
         let args: Vec<String> = env::args().collect();
@@ -114,16 +249,65 @@ pub extern "C" fn libafl_main() {
             println!("Warning: LLVMFuzzerInitialize failed with -1");
         }
 
+        let corpus_dirs = &options.corpus_dirs;
+
+        // The stage, the initial inputs and the fuzzing loop are the same for both executors,
+        // only their types differ
+        macro_rules! fuzz_with {
+            ($executor:ident) => {{
+                // Setup a basic mutator with a mutational stage
+                let mutator = StdScheduledMutator::new(havoc_mutations().merge(tokens_mutations()));
+                // The inputs of the fuzzers in `--sync-dir`, imported by the first client only
+                let sync_dirs = if cores.ids.first() == Some(&core_id) {
+                    options.sync_dir.clone()
+                } else {
+                    Vec::new()
+                };
+                let mut stages = tuple_list!(
+                    StdMutationalStage::new(mutator),
+                    AflStatsStage::new(afl_output.clone()),
+                    SyncStage::new(sync_dirs),
+                    SaveStateStage::new(state_path.clone()),
+                    // The timeouts that still hang when run again go to the hangs directory
+                    HangStage::new(timeout, hang_timeout, &hangs_dir, afl_output.is_some())?
+                );
+
+                // In case the corpus is empty (on first run and without a saved state), reset
+                if state.must_load_initial_inputs() {
+                    state
+                        .load_initial_inputs(&mut fuzzer, &mut $executor, &mut restarting_mgr, corpus_dirs)
+                        .unwrap_or_else(|_| panic!("Failed to load initial corpus at {:?}", corpus_dirs));
+                    println!("We imported {} inputs from disk.", state.corpus().count());
+                }
+                if options.forkserver {
+                    forkserver::check_coverage(&state)?;
+                }
+                fuzzer.fuzz_loop(&mut stages, &mut $executor, &mut state, &mut restarting_mgr)
+            }};
+        }
 
-        let corpus_dirs = vec![PathBuf::from("./corpus")];
-        // In case the corpus is empty (on first run), reset
-        if state.must_load_initial_inputs() {
-            state
-                .load_initial_inputs(&mut fuzzer, &mut executor, &mut restarting_mgr, &corpus_dirs)
-                .unwrap_or_else(|_| panic!("Failed to load initial corpus at {:?}", &corpus_dirs));
-            println!("We imported {} inputs from disk.", state.corpus().count());
+        if options.forkserver {
+            // A fresh fork of this binary per input, coverage map and input in shared memory
+            let mut executor = ForkserverExecutor::builder()
+                .program(env::current_exe()?)
+                .env(forkserver::TARGET_ENV, "1")
+                .shmem_provider(&mut forkserver_shmem_provider)
+                .coverage_map_size(forkserver::MAP_SIZE)
+                .timeout(timeout)
+                .is_persistent(false)
+                .build(tuple_list!(edges_observer, time_observer))?;
+            fuzz_with!(executor)
+        } else {
+            let mut executor = InProcessExecutor::with_timeout(
+                &mut harness,
+                tuple_list!(edges_observer, time_observer),
+                &mut fuzzer,
+                &mut state,
+                &mut restarting_mgr,
+                timeout,
+            )?;
+            fuzz_with!(executor)
         }
-        fuzzer.fuzz_loop(&mut stages, &mut executor, &mut state, &mut restarting_mgr)
     };
 
 
@@ -131,15 +315,49 @@ pub extern "C" fn libafl_main() {
 
     let shmem_provider = StdShMemProvider::new().expect("Failed to init shared memory");
 
-    let monitor = OnDiskTOMLMonitor::new(
-        "./fuzzer_stats.toml",
-        MultiMonitor::new(|s| println!("{s}")),
+    // The latest stats in `--stats`, their history in `--history`, shown in the terminal UI with `--tui`
+    let base_monitor = FuzzerMonitor::new(options.tui, "appsec_guide");
+    // The output of the clients would break the terminal UI
+    let quiet_clients = base_monitor.is_tui();
+    let monitor = HistoryMonitor::new(
+        options.history.clone(),
+        OnDiskTOMLMonitor::new(&options.stats, base_monitor),
     );
 
-    let broker_port = 1337;
-    let cores = Cores::all().unwrap();
+    // The main node only brokers, the nodes with clients connect to it
+    if options.role == Role::Broker {
+        let addr = SocketAddr::new(
+            options.bind.unwrap_or(Ipv4Addr::LOCALHOST.into()),
+            options.broker_port,
+        );
+        println!(
+            "Main node listening on {addr}, connect the others with --role client --remote-broker <this host>:{}",
+            options.broker_port
+        );
+        match run_broker(shmem_provider, monitor, addr) {
+            Ok(()) => (),
+            Err(Error::ShuttingDown) => println!("Fuzzing stopped by user. Good bye."),
+            Err(err) => panic!("Failed to run the broker: {err:?}"),
+        }
+        return;
+    }
+
+    // The queues of an earlier run are only used again with `--resume`
+    for &core_id in &cores.ids {
+        resume::check_queue(
+            &client_queue_dir(&options, core_id),
+            options.resume,
+            options.force,
+        )
+        .expect("Invalid fuzzer configuration");
+    }
 
-    let addr: SocketAddr = "192.168.0.101:1337".parse().expect("Invalid ip");
+    // Every other node runs its own broker with its clients, the one of a client node
+    // connects to the main node (clap requires `--remote-broker` for `--role client`)
+    let remote_broker = options.remote_broker;
+    if let Some(addr) = remote_broker {
+        println!("Connecting to the main node at {addr}");
+    }
 
     match Launcher::builder()
         .shmem_provider(shmem_provider)
@@ -147,10 +365,9 @@ pub extern "C" fn libafl_main() {
         .monitor(monitor)
         .run_client(&mut run_client)
         .cores(&cores)
-        .broker_port(broker_port)
-        .remote_broker_addr(None) //server atau broker
-        //.remote_broker_addr(Some(addr)) //client
-        //.stdout_file(Some("/dev/null"))
+        .broker_port(options.broker_port)
+        .remote_broker_addr(remote_broker)
+        .stdout_file(quiet_clients.then_some("/dev/null"))
         .build()
         .launch()
     {
@@ -158,4 +375,33 @@ pub extern "C" fn libafl_main() {
         Err(Error::ShuttingDown) => println!("Fuzzing stopped by user. Good bye."),
         Err(err) => panic!("Failed to run launcher: {err:?}"),
     }
-}
\ No newline at end of file
+}
+
+/// The queue of the client on `core_id`, the `queue/` of its AFL++ instance with `--afl-out`
+#[cfg(not(test))]
+fn client_queue_dir(options: &FuzzerOptions, core_id: CoreId) -> PathBuf {
+    match &options.afl_out {
+        Some(out) => out.join(format!("core{}", core_id.0)).join("queue"),
+        None => options.queue.join(format!("core{}", core_id.0)),
+    }
+}
+
+/// The broker of `--role broker`, without clients of its own, listening on `addr`
+#[cfg(not(test))]
+fn run_broker<MT>(
+    shmem_provider: StdShMemProvider,
+    monitor: MT,
+    addr: SocketAddr,
+) -> Result<(), Error>
+where
+    MT: Monitor,
+{
+    let mut broker = LlmpBroker::new(
+        shmem_provider,
+        tuple_list!(StdLlmpEventHook::<BytesInput, MT>::new(monitor)?),
+    )?;
+    broker.inner_mut().launch_tcp_listener(TcpListener::bind(addr)?)?;
+    // Like the broker of the Launcher: clients gone for 30 s are dropped
+    broker.loop_with_timeouts(Duration::from_secs(30), Some(Duration::from_millis(5)));
+    Ok(())
+}
@@ -0,0 +1,116 @@
+//! Command line of the appsec guide fuzzer
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+use clap::{Parser, ValueEnum};
+
+/// What this node runs in a campaign
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Role {
+    /// A broker and its clients, on their own
+    Standalone,
+    /// The main node of a distributed campaign: only a broker, listening on `--bind`, the
+    /// brokers of the other nodes connect to it
+    Broker,
+    /// A broker and its clients, connected to the broker of the main node
+    Client,
+}
+
+#[derive(Debug, Parser)]
+#[command(name = "fuzz", about = "LibAFL fuzzer for the appsec guide harness")]
+pub struct FuzzerOptions {
+    /// Run the files and directories given as CORPUS_DIR (e.g. ./crashes) and exit
+    #[arg(long)]
+    pub replay: bool,
+
+    /// Triage the crashes given as CORPUS_DIR (e.g. ./crashes) into clusters in this
+    /// directory and exit, best with a `LIBAFL_SANITIZER=address` build
+    #[arg(long, value_name = "OUTPUT_DIR")]
+    pub triage: Option<PathBuf>,
+
+    /// Role of this node in a distributed campaign
+    #[arg(long, value_enum, default_value_t = Role::Standalone)]
+    pub role: Role,
+
+    /// Port of the llmp broker of this node
+    #[arg(short = 'p', long, default_value_t = 1337)]
+    pub broker_port: u16,
+
+    /// Address the broker of `--role broker` listens on, e.g. `0.0.0.0` for all interfaces
+    /// (default: 127.0.0.1)
+    #[arg(long, value_name = "ADDR")]
+    pub bind: Option<IpAddr>,
+
+    /// Broker of the main node for `--role client`, e.g. `192.168.0.101:1337`
+    #[arg(long, required_if_eq("role", "client"))]
+    pub remote_broker: Option<SocketAddr>,
+
+    /// Cores to run a client on, e.g. `all`, `0-3` or `0,2,4`
+    #[arg(long, default_value = "all")]
+    pub cores: String,
+
+    /// File the stats of all clients are written to
+    #[arg(long, default_value = "./fuzzer_stats.toml")]
+    pub stats: PathBuf,
+
+    /// Show the stats in a terminal UI (stat lines when stdout is not a terminal)
+    #[arg(long)]
+    pub tui: bool,
+
+    /// Also append timestamped per-client and total stats to this CSV file every 10 s
+    #[arg(long, value_name = "FILE")]
+    pub history: Option<PathBuf>,
+
+    /// Run the target out of process, in a fresh fork of this binary for every input
+    /// (forkserver build only)
+    #[arg(long)]
+    pub forkserver: bool,
+
+    /// Seed of the random number generator, the clients derive theirs from it (default: random)
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Directories of other fuzzers to import new inputs from, an AFL++ output directory
+    /// or a plain one (can be repeated), only the first client imports them
+    #[arg(long, value_name = "DIR", value_delimiter = ',')]
+    pub sync_dir: Vec<PathBuf>,
+
+    /// Directory of the queue, every client keeps its entries and its saved state in
+    /// `<QUEUE>/core<N>/` (`queue/` with `--afl-out`)
+    #[arg(long, value_name = "QUEUE", default_value = "./queue")]
+    pub queue: PathBuf,
+
+    /// Go on from the queue and the state saved by the previous run instead of
+    /// importing the initial inputs again
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Remove the queue of an earlier run and start over, without it (or `--resume`) a queue
+    /// with entries is refused
+    #[arg(long, conflicts_with = "resume")]
+    pub force: bool,
+
+    /// Directory where the confirmed timeouts are stored (`hangs/` with `--afl-out`)
+    #[arg(long, default_value = "./hangs")]
+    pub hangs: PathBuf,
+
+    /// Timeout of each run of the target in milliseconds, the inputs that run longer are run
+    /// again with `--hang-timeout`
+    #[arg(short = 't', long, default_value_t = 10_000)]
+    pub timeout: u64,
+
+    /// Timeout of the re-run that confirms a hang, in milliseconds (default: twice `--timeout`)
+    #[arg(long)]
+    pub hang_timeout: Option<u64>,
+
+    /// Also write an AFL++ output directory (`<AFL_OUT>/core<N>/` per client) with AFL-style
+    /// names, `fuzzer_stats` and `plot_data`; the crashes go to its `crashes/`
+    #[arg(long, value_name = "AFL_OUT")]
+    pub afl_out: Option<PathBuf>,
+
+    /// Corpus directories with the initial inputs (files too with `--replay`)
+    #[arg(value_name = "CORPUS_DIR", default_value = "./corpus")]
+    pub corpus_dirs: Vec<PathBuf>,
+}
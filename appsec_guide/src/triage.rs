@@ -0,0 +1,393 @@
+//! Crash triage (`--triage`), in the style of CASR: one representative per bug.
+//!
+//! Every crash runs once more in a fresh process of this binary (the target mode of
+//! [`lab_common::forkserver`]) and libcasr reads its ASan report, as `casr-san` does: the
+//! severity class (`EXPLOITABLE`, `PROBABLY_EXPLOITABLE`, `NOT_EXPLOITABLE`) with its short
+//! description, the stack and the crash line (the first frame of the target below the
+//! runtimes). A signature hashes the top frames. Crashes with the same crash line form a
+//! cluster `cl<N>/`, with their inputs, reports and a `summary.txt`; the top-level
+//! `summary.txt` lists the clusters, the most severe first.
+//! Without ASan (`LIBAFL_SANITIZER=none`, UBSan, MSan) the class stays `UNKNOWN`.
+use core::time::Duration;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
+    env,
+    fmt::Write as _,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::Write,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    thread,
+    time::Instant,
+};
+
+use lab_common::{cmin::collect_files, forkserver};
+use libafl::Error;
+use libcasr::{
+    asan::{AsanContext, AsanStacktrace},
+    init_ignored_frames,
+    severity::Severity,
+    stacktrace::{CrashLine, CrashLineExt, Filter, ParseStacktrace},
+};
+
+/// Number of frames that make up the stack signature
+const SIGNATURE_DEPTH: usize = 5;
+
+/// How often the re-run is polled for its end
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The class of a crash without an ASan report
+const UNKNOWN: &str = "UNKNOWN";
+
+/// What the report of one crash tells
+#[derive(Debug)]
+struct CrashInfo {
+    path: PathBuf,
+    size: u64,
+    /// The CASR short description, e.g. `heap-buffer-overflow(write)`, or the signal
+    bug_type: String,
+    crash_line: String,
+    signature: String,
+    frames: Vec<String>,
+    /// The CASR severity class, e.g. `EXPLOITABLE`
+    severity: String,
+    report: String,
+}
+
+/// Triage the crashes in `paths` (files or directories) into `output_dir`
+pub fn triage(paths: &[PathBuf], output_dir: &Path, timeout: Duration) -> Result<(), Error> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            collect_files(path, &mut files)?;
+        } else {
+            files.push((path.clone(), fs::metadata(path)?.len()));
+        }
+    }
+    fs::create_dir_all(output_dir)?;
+
+    let mut crashes = Vec::new();
+    let mut not_reproduced = Vec::new();
+    for (path, size) in files {
+        let (status, report) = run(&fs::read(&path)?, output_dir, timeout)?;
+        match status {
+            Some(status) if !status.success() => {
+                crashes.push(analyze(path, size, status, report));
+            }
+            _ => not_reproduced.push(path),
+        }
+    }
+
+    // Same crash line, same bug; the clusters in order of severity, then of size
+    let mut clusters: BTreeMap<String, Vec<CrashInfo>> = BTreeMap::new();
+    for crash in crashes {
+        clusters
+            .entry(crash.crash_line.clone())
+            .or_default()
+            .push(crash);
+    }
+    let mut clusters: Vec<Vec<CrashInfo>> = clusters.into_values().collect();
+    for cluster in &mut clusters {
+        cluster.sort_by_key(|crash| (severity_rank(&crash.severity), crash.size));
+    }
+    clusters.sort_by_key(|cluster| {
+        (
+            severity_rank(&cluster[0].severity),
+            std::cmp::Reverse(cluster.len()),
+        )
+    });
+
+    let mut summary = String::new();
+    for (i, cluster) in clusters.iter().enumerate() {
+        let dir = output_dir.join(format!("cl{}", i + 1));
+        fs::create_dir_all(&dir)?;
+        let representative = &cluster[0];
+        let signatures: BTreeSet<&str> = cluster.iter().map(|c| c.signature.as_str()).collect();
+
+        let mut text = String::new();
+        writeln!(text, "crash line:     {}", representative.crash_line).unwrap();
+        writeln!(text, "bug type:       {}", representative.bug_type).unwrap();
+        writeln!(text, "severity:       {}", representative.severity).unwrap();
+        writeln!(text, "crashes:        {}", cluster.len()).unwrap();
+        writeln!(text, "stacks:         {}", signatures.len()).unwrap();
+        writeln!(text, "representative: {}", representative.path.display()).unwrap();
+        writeln!(text, "\nstack of the representative:").unwrap();
+        for frame in &representative.frames {
+            writeln!(text, "  {frame}").unwrap();
+        }
+        writeln!(text, "\ncrashes:").unwrap();
+        for crash in cluster {
+            let name = crash.path.file_name().unwrap_or_default();
+            fs::copy(&crash.path, dir.join(name))?;
+            let mut report_name = name.to_os_string();
+            report_name.push(".report");
+            fs::write(dir.join(report_name), &crash.report)?;
+            writeln!(
+                text,
+                "  {} {} {} bytes, stack {}",
+                crash.path.display(),
+                crash.severity,
+                crash.size,
+                crash.signature
+            )
+            .unwrap();
+        }
+        fs::write(dir.join("summary.txt"), text)?;
+
+        writeln!(
+            summary,
+            "cl{:<4} {:<21} {:<4} crashes  {:<24} {}",
+            i + 1,
+            representative.severity,
+            cluster.len(),
+            representative.bug_type,
+            representative.crash_line
+        )
+        .unwrap();
+    }
+    for path in &not_reproduced {
+        writeln!(summary, "not reproduced: {}", path.display()).unwrap();
+    }
+    fs::write(output_dir.join("summary.txt"), &summary)?;
+    print!("{summary}");
+    println!(
+        "triage: {} clusters, {} not reproduced, written to {}",
+        clusters.len(),
+        not_reproduced.len(),
+        output_dir.display()
+    );
+    Ok(())
+}
+
+/// Run the input in a fresh target process, its exit status (none on timeout) and stderr
+fn run(
+    input: &[u8],
+    output_dir: &Path,
+    timeout: Duration,
+) -> Result<(Option<ExitStatus>, String), Error> {
+    let stderr_path = output_dir.join(".stderr");
+    let mut child = Command::new(env::current_exe()?)
+        .env(forkserver::TARGET_ENV, "1")
+        .env_remove("__AFL_SHM_ID")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(File::create(&stderr_path)?)
+        .spawn()?;
+    // A target that dies before reading all of its input, ignore the broken pipe
+    let _ = child.stdin.take().unwrap().write_all(input);
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            break None;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    let report = String::from_utf8_lossy(&fs::read(&stderr_path)?).into_owned();
+    fs::remove_file(&stderr_path)?;
+    Ok((status, report))
+}
+
+/// Parse the sanitizer report of a crash with libcasr
+fn analyze(path: PathBuf, size: u64, status: ExitStatus, report: String) -> CrashInfo {
+    init_ignored_frames!("cpp", "rust");
+
+    let signal = || match status.signal() {
+        Some(signal) => signal_name(signal),
+        None => format!("exit-{}", status.code().unwrap_or(-1)),
+    };
+    // The report from its `ERROR:` line on, as `casr-san` hands it to libcasr
+    let lines: Vec<String> = report
+        .lines()
+        .map(|line| line.trim_end().to_string())
+        .collect();
+    let start = lines.iter().position(|line| {
+        line.contains("ERROR: AddressSanitizer: ") || line.contains("ERROR: LeakSanitizer: ")
+    });
+
+    let (bug_type, severity, stacktrace) = match start {
+        Some(start) => {
+            let asan_report = lines[start..].to_vec();
+            let class = AsanContext(asan_report.clone()).severity().ok();
+            let stacktrace = AsanStacktrace::extract_stacktrace(&asan_report.join("\n"))
+                .and_then(|entries| AsanStacktrace::parse_stacktrace(&entries))
+                .ok();
+            match class {
+                Some(class) => (class.short_description, class.severity, stacktrace),
+                None => (signal(), UNKNOWN.to_string(), stacktrace),
+            }
+        }
+        // UBSan, MSan or no sanitizer: the class is not known, the stack still is
+        None => {
+            let bug_type = if report.contains("runtime error:") {
+                "undefined-behavior".to_string()
+            } else if let Some((_, rest)) = report.split_once("ERROR: MemorySanitizer: ") {
+                rest.split_whitespace()
+                    .next()
+                    .unwrap_or("unknown")
+                    .to_string()
+            } else {
+                signal()
+            };
+            let stacktrace = AsanStacktrace::extract_stacktrace(&report)
+                .and_then(|entries| AsanStacktrace::parse_stacktrace(&entries))
+                .ok();
+            (bug_type, UNKNOWN.to_string(), stacktrace)
+        }
+    };
+
+    // The first frame of the target, libcasr skips the runtimes and libc
+    let crash_line = stacktrace
+        .as_ref()
+        .and_then(|stacktrace| stacktrace.crash_line().ok())
+        .map(|crash_line| match crash_line {
+            CrashLine::Source(debug) => format!("{}:{}", debug.file, debug.line),
+            CrashLine::Module { file, offset } => format!("{file}+{offset:#x}"),
+        })
+        .unwrap_or_else(|| bug_type.clone());
+
+    let frames: Vec<String> = stacktrace
+        .map(|mut stacktrace| {
+            stacktrace.filter();
+            stacktrace
+                .iter()
+                .map(|entry| {
+                    if entry.debug.file.is_empty() {
+                        format!("{} {}", entry.function, entry.module)
+                    } else {
+                        format!(
+                            "{} {}:{}",
+                            entry.function, entry.debug.file, entry.debug.line
+                        )
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut hasher = DefaultHasher::new();
+    for frame in frames.iter().take(SIGNATURE_DEPTH) {
+        frame.split(' ').next().hash(&mut hasher);
+    }
+    let signature = format!("{:016x}", hasher.finish());
+
+    CrashInfo {
+        path,
+        size,
+        bug_type,
+        crash_line,
+        signature,
+        frames,
+        severity,
+        report,
+    }
+}
+
+/// The rank of a CASR severity class, the most severe first
+fn severity_rank(severity: &str) -> u8 {
+    match severity {
+        "EXPLOITABLE" => 0,
+        "PROBABLY_EXPLOITABLE" => 1,
+        "NOT_EXPLOITABLE" => 2,
+        _ => 3,
+    }
+}
+
+fn signal_name(signal: i32) -> String {
+    match signal {
+        libc::SIGSEGV => "SIGSEGV".to_string(),
+        libc::SIGBUS => "SIGBUS".to_string(),
+        libc::SIGABRT => "SIGABRT".to_string(),
+        libc::SIGFPE => "SIGFPE".to_string(),
+        libc::SIGILL => "SIGILL".to_string(),
+        signal => format!("signal-{signal}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::process::ExitStatusExt, path::PathBuf, process::ExitStatus};
+
+    use super::analyze;
+
+    const HEAP_OVERFLOW: &str = "\
+=================================================================
+==12345==ERROR: AddressSanitizer: heap-buffer-overflow on address 0x602000000031 at pc 0x55d5c0a1f2b3 bp 0x7ffd3a9c8f10 sp 0x7ffd3a9c86c0
+WRITE of size 4 at 0x602000000031 thread T0
+    #0 0x55d5c0a1f2b2 in __asan_memcpy /src/llvm-project/compiler-rt/lib/asan/asan_interceptors_memintrinsics.cpp:63:3
+    #1 0x55d5c0a6b1c7 in parse_header /src/appsec/harness.cc:42:7
+    #2 0x55d5c0a6b5e1 in LLVMFuzzerTestOneInput /src/appsec/harness.cc:87:3
+    #3 0x55d5c0a2c8f0 in libafl_targets::libfuzzer::libfuzzer_test_one_input /root/.cargo/registry/src/index.crates.io-6f17d22bba15001f/libafl_targets-0.13.2/src/libfuzzer/mod.rs:48:14
+    #4 0x7f1c2d429d8f in __libc_start_call_main csu/../sysdeps/nptl/libc_start_call_main.h:58:16
+
+0x602000000031 is located 0 bytes to the right of 1-byte region [0x602000000030,0x602000000031)
+allocated by thread T0 here:
+    #0 0x55d5c0a20a6d in malloc /src/llvm-project/compiler-rt/lib/asan/asan_malloc_linux.cpp:69:3
+    #1 0x55d5c0a6b190 in parse_header /src/appsec/harness.cc:40:18
+
+SUMMARY: AddressSanitizer: heap-buffer-overflow /src/appsec/harness.cc:42:7 in parse_header
+Shadow bytes around the buggy address:
+  0x0c047fff7fb0: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+=>0x0c047fff8000: fa fa 05 fa fa fa fa fa fa fa fa fa fa fa fa fa
+==12345==ABORTING
+";
+
+    const NULL_READ: &str = "\
+AddressSanitizer:DEADLYSIGNAL
+=================================================================
+==4242==ERROR: AddressSanitizer: SEGV on unknown address 0x000000000000 (pc 0x55e0d1c6b2a4 bp 0x7ffc8e1b2c40 sp 0x7ffc8e1b2c20 T0)
+==4242==The signal is caused by a READ memory access.
+==4242==Hint: address points to the zero page.
+    #0 0x55e0d1c6b2a4 in check_magic /src/appsec/harness.cc:21:10
+    #1 0x55e0d1c6b5e1 in LLVMFuzzerTestOneInput /src/appsec/harness.cc:85:7
+    #2 0x55e0d1c2c8f0 in libafl_targets::libfuzzer::libfuzzer_test_one_input /root/.cargo/registry/src/index.crates.io-6f17d22bba15001f/libafl_targets-0.13.2/src/libfuzzer/mod.rs:48:14
+
+AddressSanitizer can not provide additional info.
+SUMMARY: AddressSanitizer: SEGV /src/appsec/harness.cc:21:10 in check_magic
+==4242==ABORTING
+";
+
+    fn aborted() -> ExitStatus {
+        ExitStatus::from_raw(libc::SIGABRT)
+    }
+
+    #[test]
+    fn heap_overflow_write_is_exploitable() {
+        let crash = analyze(PathBuf::from("a"), 4, aborted(), HEAP_OVERFLOW.to_string());
+        assert_eq!(crash.severity, "EXPLOITABLE");
+        assert!(crash.bug_type.starts_with("heap-buffer-overflow"));
+        assert_eq!(crash.crash_line, "/src/appsec/harness.cc:42");
+        assert!(crash.frames[0].starts_with("parse_header "));
+    }
+
+    #[test]
+    fn null_read_is_not_exploitable() {
+        let crash = analyze(PathBuf::from("b"), 4, aborted(), NULL_READ.to_string());
+        assert_eq!(crash.severity, "NOT_EXPLOITABLE");
+        assert_eq!(crash.crash_line, "/src/appsec/harness.cc:21");
+    }
+
+    #[test]
+    fn same_crash_line_same_signature() {
+        let first = analyze(PathBuf::from("a"), 4, aborted(), HEAP_OVERFLOW.to_string());
+        let second = analyze(PathBuf::from("c"), 9, aborted(), HEAP_OVERFLOW.to_string());
+        let other = analyze(PathBuf::from("b"), 4, aborted(), NULL_READ.to_string());
+        assert_eq!(first.signature, second.signature);
+        assert_ne!(first.signature, other.signature);
+    }
+
+    #[test]
+    fn without_report_only_the_signal() {
+        let status = ExitStatus::from_raw(libc::SIGSEGV);
+        let crash = analyze(PathBuf::from("d"), 1, status, String::new());
+        assert_eq!(crash.bug_type, "SIGSEGV");
+        assert_eq!(crash.severity, "UNKNOWN");
+        assert_eq!(crash.crash_line, "SIGSEGV");
+    }
+}
@@ -23,13 +23,14 @@ use libafl::{
 };
 
 use libafl_bolts::{
+    current_nanos,
     rands::StdRand,
     AsSlice,
     nonzero,
     tuples::tuple_list,
 };
 
-use std::{ path::PathBuf, ptr::write };
+use std::{ env, path::PathBuf, ptr::write };
 
 
 // Coverage map with explicit assignments due to the lack of instrumentation
@@ -72,8 +73,15 @@ fn main()
     let mut objective = CrashFeedback::new();
 
 
+    // Seed RNG dari argumen pertama, atau dari jam. Seed yang sama = mutasi yang sama
+    let seed = match env::args().nth(1) {
+        Some(arg) => arg.parse().expect("The seed must be a number"),
+        None => current_nanos(),
+    };
+    println!("Seed: {seed} (repeat with: cargo run -- {seed})");
+
     let mut state = StdState::new(
-        StdRand::new(),
+        StdRand::with_seed(seed),
         InMemoryCorpus::<BytesInput>::new(),
         OnDiskCorpus::new(PathBuf::from("./crashes")).unwrap(),
         &mut feedback,
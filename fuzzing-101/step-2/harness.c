@@ -6,6 +6,17 @@
 
 #include "libexif/exif-data.h"
 #include "libexif/exif-loader.h"
+
+// input ditulis oleh fuzzer (rust, tanpa instrumentasi), tanpa ini MSan menganggapnya belum diinisialisasi
+#if defined(__has_feature)
+#if __has_feature(memory_sanitizer)
+#include <sanitizer/msan_interface.h>
+#define UNPOISON_INPUT(data, size) __msan_unpoison(data, size)
+#endif
+#endif
+#ifndef UNPOISON_INPUT
+#define UNPOISON_INPUT(data, size)
+#endif
 // removed the include for "libexif/exif-system.h" because it doesn't exist in this version
 // 
 // need to add exif-system.h's #define manually 
@@ -70,6 +81,7 @@ int LLVMFuzzerTestOneInput(const uint8_t *data, size_t size) {
 	FILE		*f;
 	struct		stat stbuf;
 
+    UNPOISON_INPUT(data, size);
     d = exif_data_new_from_data(data, size);
 
     /* try the exif loader */
@@ -1,4 +1,4 @@
-use libafl_cc::{ClangWrapper, CompilerWrapper, ToolWrapper};
+use libafl_cc::{ClangWrapper, CompilerWrapper, LLVMPasses, ToolWrapper};
 use std::env;
 
 pub fn main()
@@ -16,18 +16,49 @@ pub fn main()
 
     let mut cc = ClangWrapper::new();
 
-    if let Some(code) = cc
-        .cpp(false)
+    cc.cpp(false)
         // silence the compiler wrapper output, needed for some configure scripts.
         .silence(true)
         .parse_args(&args)
         .expect("Failed to parse the command line")
         .link_staticlib(&cwd, "exercisetwo")
-        .add_arg("-fsanitize-coverage=trace-pc-guard")
-        .add_arg("-fsanitize=address")
-        .run()
-        .expect("Failed to run the wrapped compiler")
-    {
+        .add_arg("-fsanitize-coverage=trace-pc-guard");
+
+    // sanitizer build: address (default), undefined, memory atau none
+    // opsi runtime-nya (abort_on_error, halt_on_error) diset oleh fuzzer, lihat lab-common/src/sanitizers.rs
+    let sanitizer = env::var("LIBAFL_SANITIZER").unwrap_or_else(|_| "address".into());
+    let sanitizer_flags: &[&str] = match sanitizer.as_str() {
+        "address" => &["-fsanitize=address"],
+        "undefined" => &["-fsanitize=undefined"],
+        "memory" => &["-fsanitize=memory", "-fsanitize-memory-track-origins"],
+        "none" => &[],
+        other => panic!("Unknown LIBAFL_SANITIZER {other:?}, expected address, undefined, memory or none"),
+    };
+    cc.add_args(sanitizer_flags);
+
+    // build kedua (tracing) untuk stage cmplog: rekam semua perbandingan
+    if env::var("LIBAFL_CMPLOG").is_ok_and(|val| val == "1") {
+        cc.add_arg("-fsanitize-coverage=trace-cmp");
+    }
+
+    // simpan konstanta dari operand perbandingan (tag, magic string) ke binary, dibaca lagi oleh autotokens()
+    if env::var("LIBAFL_AUTOTOKENS").is_ok_and(|val| val == "1") {
+        cc.add_pass(LLVMPasses::AutoTokens);
+    }
+
+    // build ketiga (coverage) untuk --coverage, -rdynamic supaya replay bisa menemukan runtime profile
+    if env::var("LIBAFL_COVERAGE").is_ok_and(|val| val == "1") {
+        cc.add_arg("-fprofile-instr-generate")
+            .add_arg("-fcoverage-mapping")
+            .add_link_arg("-rdynamic");
+    }
+
+    // build forkserver: simbol diekspor supaya dladdr di child yg crash menemukan nama frame
+    if cfg!(feature = "forkserver") {
+        cc.add_link_arg("-rdynamic");
+    }
+
+    if let Some(code) = cc.run().expect("Failed to run the wrapped compiler") {
         std::process::exit(code);
     }
 }
@@ -1,22 +1,48 @@
+use std::env;
 use std::path::PathBuf;
 use std::time::Duration;
 
-use libafl::corpus::{Corpus, InMemoryCorpus, OnDiskCorpus};
+use libafl::corpus::{Corpus, InMemoryOnDiskCorpus, OnDiskCorpus};
 use libafl::events::{setup_restarting_mgr_std, EventConfig, EventRestarter};
-use libafl::executors::{ExitKind, InProcessExecutor};
+use libafl::executors::{ExitKind, ForkserverExecutor, InProcessExecutor};
 use libafl::feedbacks::{CrashFeedback, MaxMapFeedback, TimeFeedback};
 use libafl::inputs::{BytesInput, HasTargetBytes};
-use libafl::monitors::MultiMonitor;
-use libafl::mutators::{havoc_mutations, StdScheduledMutator};
-use libafl::observers::{CanTrack, HitcountsMapObserver, TimeObserver};
+use libafl::mutators::{
+    havoc_mutations, tokens_mutations, I2SRandReplace, StdScheduledMutator, Tokens,
+};
+use libafl::observers::{CanTrack, HitcountsMapObserver, StdMapObserver, TimeObserver};
 use libafl::schedulers::{IndexesLenTimeMinimizerScheduler, QueueScheduler};
-use libafl::stages::StdMutationalStage;
+use libafl::stages::{IfStage, StdMutationalStage, TracingStage};
 use libafl::state::{HasCorpus, StdState};
-use libafl::{feedback_and_fast, feedback_or, Error, Fuzzer, StdFuzzer};
+use libafl::{feedback_and_fast, feedback_or, Error, Fuzzer, HasMetadata, StdFuzzer};
 use libafl_bolts::rands::StdRand;
-use libafl_bolts::tuples::tuple_list;
-use libafl_bolts::{current_nanos, AsSlice};
-use libafl_targets::{libfuzzer_test_one_input, std_edges_map_observer};
+use libafl_bolts::tuples::{tuple_list, Merge};
+use libafl_bolts::shmem::{ShMemProvider, UnixShMemProvider};
+use libafl_bolts::{AsSlice, AsSliceMut};
+#[cfg(any(target_os = "linux", target_vendor = "apple"))]
+use libafl_targets::autotokens;
+use libafl_targets::{libfuzzer_test_one_input, std_edges_map_observer, CmpLogMap, CmpLogObserver};
+
+use lab_common::afl_output::{AflCorpus, AflNameFeedback, AflOutput, AflStatsStage};
+use lab_common::dedup::{StackHashObserver, UniqueCrashFeedback};
+use lab_common::monitor::FuzzerMonitor;
+use lab_common::resume::{self, SaveStateStage};
+use lab_common::sanitizers::{SanitizerReportFeedback, SanitizerReportObserver};
+use lab_common::seed::{self, SeedMetadata};
+use lab_common::sync::{SyncOriginFeedback, SyncStage};
+use lab_common::forkserver::{self, ChildCrashInfo};
+use lab_common::coverage;
+use lab_common::cmin::{self, EdgesObserver};
+use lab_common::tmin;
+use lab_common::replay;
+
+mod options;
+use clap::Parser;
+use options::FuzzerOptions;
+
+fn edges_observer() -> EdgesObserver { //peta edge dari harness, untuk mode yg hanya menjalankan input
+    HitcountsMapObserver::new(unsafe { std_edges_map_observer("edges") })
+}
 
 #[no_mangle]
 fn libafl_main() -> Result<(), Error> {
@@ -30,39 +56,99 @@ fn libafl_main() -> Result<(), Error> {
 		- menyimpan hasil explorasi dan crash.
 	*/
 
+    if env::var_os(forkserver::TARGET_ENV).is_some() { //dijalankan oleh ForkserverExecutor (--forkserver) sebagai target
+        forkserver::run_target();
+    }
+
+    let options = FuzzerOptions::parse();
+    if options.forkserver {
+        forkserver::check_build()?; //--forkserver hanya dengan build forkserver (cargo make mybuild-forkserver)
+    }
+
+    if let Some(output_dir) = &options.cmin { //minimisasi corpus saja, tanpa fuzzing
+        return cmin::minimize(&options.corpus_dirs, output_dir, Duration::from_millis(5000), edges_observer);
+    }
+    if !options.tmin.is_empty() { //minimisasi crash di ./solutions, hasilnya <crash>.min
+        return tmin::minimize_crashes(&options.tmin, Duration::from_millis(5000), options.crash_depth);
+    }
+    if options.replay { //jalankan input (misal ./solutions) tanpa fuzzing, exit 1 jika ada crash/timeout
+        if !replay::replay(&options.corpus_dirs, Duration::from_millis(5000), None, edges_observer)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(output_dir) = &options.coverage { //laporan coverage lcov/html dari corpus, pakai build LIBAFL_COVERAGE=1
+        return coverage::report(
+            &options.corpus_dirs,
+            options.coverage_base.as_deref(),
+            output_dir,
+            Duration::from_millis(5000),
+            edges_observer,
+        );
+    }
 
 
 
 
     // Component: Corpus
-    let corpus_dirs = vec![PathBuf::from("./corpus")]; //lokasi awal input
-    let input_corpus = InMemoryCorpus::<BytesInput>::new(); //corpus yg akan difuzz, disimpan dimemori
-    let solutions_corpus = OnDiskCorpus::new(PathBuf::from("./solutions")).unwrap(); //corpus solusi (misalnya crash, coverage baru), disimpan didisk
+    let corpus_dirs = options.corpus_dirs.clone(); //lokasi awal input (default ./corpus)
+    let afl_output = options.afl_out.as_deref().map(|out| AflOutput::create(out, "default", "fuzzer_libexif")).transpose()?; //--afl-out: layout output AFL++
+    let queue_dir = afl_output.as_ref().map_or_else(|| options.queue.clone(), AflOutput::queue);
+    resume::check_queue(&queue_dir, options.resume, options.force)?; //queue dari run sebelumnya hanya dipakai dengan --resume, --force menghapusnya
+    let input_corpus = InMemoryOnDiskCorpus::<BytesInput>::new(&queue_dir)?; //corpus yg akan difuzz, dimemori dan disalin ke disk (--queue) agar bisa dilanjutkan
+    let state_path = resume::state_path(&queue_dir); //state (tanpa input) disimpan tiap 60 detik di <queue>/.state
+    let solutions_dir = afl_output.as_ref().map_or_else(|| PathBuf::from("./solutions"), AflOutput::crashes);
+    let solutions_corpus = OnDiskCorpus::new(&solutions_dir).unwrap(); //corpus solusi (misalnya crash, coverage baru), disimpan didisk
 
 
     // Component: Observer
+    let mut forkserver_shmem_provider = UnixShMemProvider::new()?;
+    let mut coverage_shmem = if options.forkserver { //dengan --forkserver peta coverage dibagi dengan proses target
+        Some(forkserver::coverage_map(&mut forkserver_shmem_provider)?)
+    } else {
+        None
+    };
     let edges_observer = HitcountsMapObserver::new(unsafe { //mengamati coverage dengan memanfaatkan sancov instrumnen
-    	std_edges_map_observer("edges")
+        match &mut coverage_shmem {
+            Some(shmem) => StdMapObserver::from_mut_ptr("edges", shmem.as_slice_mut().as_mut_ptr(), forkserver::MAP_SIZE),
+            None => std_edges_map_observer("edges"),
+        }
     }).track_indices();
 
     let time_observer = TimeObserver::new("time"); //mengukur wakti eksekusi input
 
+    let cmplog_observer = CmpLogObserver::new("cmplog", true); //merekam operand perbandingan (build LIBAFL_CMPLOG=1)
+
+    let stack_observer = StackHashObserver::new("stack", options.crash_depth); //hash dari frame teratas stack saat crash
+
+    let report_observer = SanitizerReportObserver::new("asan"); //teks laporan ASan saat crash (bukan hanya ke stderr)
+
 
     // Component: Feedback
     let mut feedback = feedback_or!(  //menentukan apakah sebuah input bernilai cukup menarik untuk disimpan
         MaxMapFeedback::new(&edges_observer),
-        TimeFeedback::new(&time_observer)
+        TimeFeedback::new(&time_observer),
+        AflNameFeedback::new(AflCorpus::Queue, afl_output.is_some()), //nama id:...,src:... (hanya dengan --afl-out)
+        SyncOriginFeedback::new() //asal input dari --sync-dir, ikut tersimpan di .metadata
     );
 
-    let mut objective = feedback_and_fast!(CrashFeedback::new(), MaxMapFeedback::new(&edges_observer));
+    let mut objective = feedback_or!(
+        feedback_and_fast!(
+            CrashFeedback::new(),
+            UniqueCrashFeedback::new(&stack_observer, &solutions_dir)?, //hash yg sudah ada di <solutions>/.hashes tidak disimpan lagi
+            SanitizerReportFeedback::new(&report_observer)
+        ),
+        AflNameFeedback::new(AflCorpus::Crashes, afl_output.is_some()),
+        SyncOriginFeedback::new()
+    );
     // menentukan apakah input dianggap berhasil (misalnya crash) dan ditandai sebagai solusi.
+    // crash hanya disimpan jika hash stack-nya belum pernah terlihat (bug yg sama tidak disimpan ratusan kali)
+    // jenis bug, akses, ukuran, frame dan laporan ASan lengkap disimpan di .metadata solusi
 
 
 
     // Component: Monitor
-    let monitor = MultiMonitor::new(|s| { //mencetak output ke stdout
-        println!("{}", s);
-    });
+    let monitor = FuzzerMonitor::new(options.tui, "fuzzer_libexif"); //mencetak output ke stdout, atau tampilan TUI dengan --tui
 
     // Component: EventManager
     /* mgr = EventManager mengatur komunikasi antara fuzzer dan thread / worker lain
@@ -83,16 +169,48 @@ fn libafl_main() -> Result<(), Error> {
 
 
     // Component: State
-    let mut state = state.unwrap_or_else(|| { //menyimpan corpus, RNG(angka acak), feedback state, dsb
-        StdState::new(                        //diinisialisasi saat pertama kali, atau direstorisasi dari restart
-            StdRand::with_seed(current_nanos()),
-            input_corpus,
-            solutions_corpus,
-            &mut feedback,
-            &mut objective,
-        )
-        .unwrap()
-    });
+    let seed = seed::campaign_seed(options.seed); //--seed, atau dari jam. seed yang sama = angka acak yang sama
+    let mut state = match state { //setelah restart state dari restarter
+        Some(state) => state,
+        None => {
+            let mut state = StdState::new( //menyimpan corpus, RNG(angka acak), feedback state, dsb
+                StdRand::with_seed(seed),
+                input_corpus,
+                solutions_corpus,
+                &mut feedback,
+                &mut objective,
+            )
+            .unwrap();
+            if options.resume { //--resume: entry dibaca lagi dari queue, sisanya dari state run sebelumnya
+                if resume::load_state(&state_path, &mut state)? {
+                    println!("Resuming from {}", state_path.display());
+                } else {
+                    println!("No saved state in {}, starting over", state_path.display());
+                }
+            }
+            state
+        }
+    };
+    if !state.has_metadata::<SeedMetadata>() { //setelah restart RNG lanjut dari state, seed awal tetap disimpan
+        state.add_metadata(SeedMetadata { seed, client_seed: seed }); //hanya satu client, seed dipakai langsung
+    }
+    let seed = state.metadata::<SeedMetadata>()?.seed;
+    println!("Seed: {seed} (ulangi dengan --seed {seed})");
+
+
+    // Component: Dictionary
+    if state.metadata_map().get::<Tokens>().is_none() { //token dari file dictionary (--dict), dipakai oleh tokens_mutations
+        let mut tokens = Tokens::new();
+        #[cfg(any(target_os = "linux", target_vendor = "apple"))]
+        {
+            tokens += autotokens()?; //tag dan magic string makernote dari build LIBAFL_AUTOTOKENS=1
+            println!("Found {} tokens in the target binary", tokens.len());
+        }
+        for dict in &options.dict {
+            tokens.add_from_file(dict)?;
+        }
+        state.add_metadata(tokens);
+    }
 
 
     // Component: Scheduler
@@ -111,46 +229,125 @@ fn libafl_main() -> Result<(), Error> {
         unsafe { libfuzzer_test_one_input(buffer) }; //input akan dipanggil ke fungsi C libfuzzzer_test_one_input.
         ExitKind::Ok                                 //fungsi ini berasal dari c/c++ target dan di link via libafl_targets
     };
+    let mut tracing_harness = harness; //harness yang sama untuk executor cmplog
+
+
+    // Component: Stage, loop
+    // stage, corpus awal dan loop fuzzing sama untuk kedua executor (hanya tipenya yang beda)
+    macro_rules! fuzz_with {
+        ($executor:ident, $tracing_executor:expr) => {{
+            // Component: Executor (cmplog)
+            let tracing_executor = $tracing_executor; //hanya menjalankan input untuk merekam perbandingan
+
+            if state.corpus().count() < 1 { //load corpus awal, jika corpus masih kosong (tanpa --resume) isi dari file input pada ./corpus
+                state
+                    .load_initial_inputs(&mut fuzzer, &mut $executor, &mut mgr, &corpus_dirs)
+                    .unwrap_or_else(|err| {
+                        panic!(
+                            "Failed to load initial corpus at {:?}: {:?}",
+                            &corpus_dirs, err
+                        )
+                    });
+                println!("We imported {} inputs from disk.", state.corpus().count());
+            }
+            if options.forkserver {
+                forkserver::check_coverage(&state)?; //gagal jika target forkserver tidak mengirim edge sama sekali
+            }
 
-
-    // Component: Executor
-    let mut in_proc_executor = InProcessExecutor::with_timeout( //menjalankan harness dalam proses(singgle thread), lebih cepat.
-        &mut harness,
-        tuple_list!(edges_observer, time_observer),
-        &mut fuzzer,
-        &mut state,
-        &mut mgr,
-        Duration::from_millis(5000), //timeout diset agar infinite loop/input lambat bisa dihentikan
-    )
-    .unwrap();
-
-    if state.corpus().count() < 1 { //load corpus awal, jika corpus masih kosong isi dari file input pada ./corpus
-        state
-            .load_initial_inputs(&mut fuzzer, &mut in_proc_executor, &mut mgr, &corpus_dirs)
-            .unwrap_or_else(|err| {
-                panic!(
-                    "Failed to load initial corpus at {:?}: {:?}",
-                    &corpus_dirs, err
+            // Component: Mutator
+            let mutator = StdScheduledMutator::new(havoc_mutations().merge(tokens_mutations())); //strategi mutasi acak klasik (insert,delete,bitflip, dsb) + token dictionary
+            let i2s = StdScheduledMutator::new(tuple_list!(I2SRandReplace::new())); //mengganti operand perbandingan (magic value) di input
+
+
+            // Component: Stage
+            let cmplog = options.cmplog;
+            let cmplog_stages = IfStage::new( //tracing + i2s hanya dengan --cmplog
+                move |_fuzzer, _executor, _state, _mgr| Ok(cmplog),
+                tuple_list!(TracingStage::new(tracing_executor), StdMutationalStage::new(i2s)),
+            );
+            let afl_stats = AflStatsStage::new(afl_output.clone()); //fuzzer_stats dan plot_data tiap 5 detik (hanya dengan --afl-out)
+            let sync = SyncStage::new(options.sync_dir.clone()); //tiap 30 detik jalankan input baru dari fuzzer lain (--sync-dir)
+            let save_state = SaveStateStage::new(state_path.clone()); //tiap 60 detik simpan state (metadata entry, scheduler, coverage, RNG) untuk --resume
+            let mut stages = tuple_list!(cmplog_stages, StdMutationalStage::new(mutator), afl_stats, sync, save_state); //yang akan menjalankan mutasi
+
+            fuzzer.fuzz_loop_for( //menjalankan loop fuzzing selama 1000 iterasi, bisa diganti fuzz_loop() untuk tanpa batas
+                    &mut stages,
+                    &mut $executor,
+                    &mut state,
+                    &mut mgr,
+                    1000,
                 )
-            });
-        println!("We imported {} inputs from disk.", state.corpus().count());
+                .unwrap();
+        }};
     }
 
-    // Component: Mutator
-    let mutator = StdScheduledMutator::new(havoc_mutations()); //strategi mutasi acak klasik (insert,delete,bitflip, dsb)
-
-
-    // Component: Stage
-    let mut stages = tuple_list!(StdMutationalStage::new(mutator)); //yang akan menjalankan mutasi
+    // executor cmplog dalam proses, tanpa --cmplog tidak pernah dijalankan
+    macro_rules! in_process_tracing {
+        () => {
+            InProcessExecutor::with_timeout(
+                &mut tracing_harness,
+                tuple_list!(cmplog_observer),
+                &mut fuzzer,
+                &mut state,
+                &mut mgr,
+                Duration::from_millis(50000),
+            )
+            .unwrap()
+        };
+    }
 
-    fuzzer.fuzz_loop_for( //menjalankan loop fuzzing selama 1000 iterasi, bisa diganti fuzz_loop() untuk tanpa batas
-            &mut stages,
-            &mut in_proc_executor,
+    // Component: Executor
+    if options.forkserver {
+        // libexif dijalankan di proses terpisah (fork baru per input) dari binary ini sendiri,
+        // peta coverage dan input lewat shared memory. leak/state global rusak hilang bersama child-nya
+        // frame stack dan laporan ASan dari crash ditulis child ke shared memory juga
+        let mut crash_shmem = forkserver::crash_info_map(&mut forkserver_shmem_provider)?;
+        let child = ChildCrashInfo::new(&mut crash_shmem);
+        let mut forkserver_executor = ForkserverExecutor::builder()
+            .program(env::current_exe()?)
+            .env(forkserver::TARGET_ENV, "1")
+            .env(forkserver::CRASH_SHM_ENV, forkserver::shmem_env(&crash_shmem))
+            .shmem_provider(&mut forkserver_shmem_provider)
+            .coverage_map_size(forkserver::MAP_SIZE)
+            .timeout(Duration::from_millis(5000))
+            .is_persistent(false)
+            .build(tuple_list!(
+                edges_observer,
+                time_observer,
+                stack_observer.with_child(child),
+                report_observer.with_child(child)
+            ))?;
+
+        if options.cmplog {
+            // forkserver kedua untuk cmplog, child menulis perbandingan ke shared memory
+            let mut cmplog_shmem = forkserver::cmplog_map(&mut forkserver_shmem_provider)?;
+            let cmplog_observer = unsafe {
+                CmpLogObserver::with_map_ptr("cmplog", cmplog_shmem.as_slice_mut().as_mut_ptr().cast::<CmpLogMap>(), true)
+            };
+            let tracing_executor = ForkserverExecutor::builder()
+                .program(env::current_exe()?)
+                .env(forkserver::TARGET_ENV, "1")
+                .env(forkserver::CMPLOG_SHM_ENV, forkserver::shmem_env(&cmplog_shmem))
+                .shmem_provider(&mut forkserver_shmem_provider)
+                .timeout(Duration::from_millis(50000))
+                .is_persistent(false)
+                .build(tuple_list!(cmplog_observer))?;
+            fuzz_with!(forkserver_executor, tracing_executor);
+        } else {
+            fuzz_with!(forkserver_executor, in_process_tracing!());
+        }
+    } else {
+        let mut in_proc_executor = InProcessExecutor::with_timeout( //menjalankan harness dalam proses(singgle thread), lebih cepat.
+            &mut harness,
+            tuple_list!(edges_observer, time_observer, stack_observer, report_observer),
+            &mut fuzzer,
             &mut state,
             &mut mgr,
-            1000,
+            Duration::from_millis(5000), //timeout diset agar infinite loop/input lambat bisa dihentikan
         )
         .unwrap();
+        fuzz_with!(in_proc_executor, in_process_tracing!());
+    }
     mgr.on_restart(&mut state).unwrap();
 
     Ok(())
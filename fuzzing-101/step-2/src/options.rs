@@ -0,0 +1,84 @@
+//! Command line of the libexif fuzzer
+use std::path::PathBuf;
+
+use clap::Parser;
+use lab_common::dedup;
+
+#[derive(Debug, Parser)]
+#[command(name = "fuzzer", about = "LibAFL fuzzer for libexif")]
+pub struct FuzzerOptions {
+    /// Run the CmpLog tracing and I2S stages (needs a `LIBAFL_CMPLOG=1` build)
+    #[arg(long)]
+    pub cmplog: bool,
+
+    /// Run libexif out of process, in a fresh fork of this binary for every input
+    /// (forkserver build only)
+    #[arg(long)]
+    pub forkserver: bool,
+
+    /// Show the stats of the broker in a terminal UI (stat lines when stdout is not a terminal)
+    #[arg(long)]
+    pub tui: bool,
+
+    /// AFL/libFuzzer dictionary files, e.g. the `corpus.dict` written by the libpng fuzzer
+    #[arg(short = 'x', long, value_delimiter = ',')]
+    pub dict: Vec<PathBuf>,
+
+    /// Seed of the random number generator (default: random, printed at startup)
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Directories of other fuzzers to import new inputs from, an AFL++ output directory
+    /// or a plain one
+    #[arg(long, value_name = "DIR", value_delimiter = ',')]
+    pub sync_dir: Vec<PathBuf>,
+
+    /// Number of stack frames that tell two crashes apart
+    #[arg(long, default_value_t = dedup::DEFAULT_DEPTH)]
+    pub crash_depth: usize,
+
+    /// Minimize the corpus directories into this directory and exit, like `afl-cmin`
+    #[arg(long, value_name = "OUTPUT_DIR")]
+    pub cmin: Option<PathBuf>,
+
+    /// Minimize this crash, or all crashes in this directory, to `<crash>.min` and exit
+    #[arg(long, value_name = "CRASH")]
+    pub tmin: Vec<PathBuf>,
+
+    /// Run the files and directories given as CORPUS_DIR (e.g. ./solutions) and exit
+    #[arg(long)]
+    pub replay: bool,
+
+    /// Write lcov/HTML coverage reports of the corpus directories to this directory and exit
+    /// (needs a `LIBAFL_COVERAGE=1` build)
+    #[arg(long, value_name = "OUTPUT_DIR")]
+    pub coverage: Option<PathBuf>,
+
+    /// Also measure this corpus with `--coverage` and report what only one of them reaches
+    #[arg(long, value_name = "CORPUS_DIR", requires = "coverage")]
+    pub coverage_base: Option<PathBuf>,
+
+    /// Directory of the queue, with the entries and the saved state (`queue/` with `--afl-out`)
+    #[arg(long, value_name = "QUEUE", default_value = "./queue")]
+    pub queue: PathBuf,
+
+    /// Go on from the queue and the state saved by the previous run instead of
+    /// importing the initial inputs again
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Remove the queue of an earlier run and start over, without it (or `--resume`) a queue
+    /// with entries is refused
+    #[arg(long, conflicts_with = "resume")]
+    pub force: bool,
+
+    /// Also write an AFL++ output directory (`<AFL_OUT>/default/`) with AFL-style names,
+    /// `fuzzer_stats` and `plot_data`; the queue and the solutions go to its
+    /// `queue/` and `crashes/`
+    #[arg(long, value_name = "AFL_OUT")]
+    pub afl_out: Option<PathBuf>,
+
+    /// Corpus directories with the initial inputs (files too with `--replay`)
+    #[arg(value_name = "CORPUS_DIR", default_value = "./corpus")]
+    pub corpus_dirs: Vec<PathBuf>,
+}
@@ -0,0 +1,361 @@
+//! AFL++ output directory (`--afl-out`), for `afl-whatsup`, `afl-plot` and the dashboards.
+//!
+//! Every client gets its instance directory, `<out>/core<N>/` or `<out>/default/`, with `queue/`, `crashes/` and `hangs/`, the corpora are
+//! stored there and [`AflNameFeedback`] names their entries like AFL++ does
+//! (`id:000123,src:000045,time:5120,execs:81234,op:havoc`). [`AflStatsStage`] keeps
+//! `fuzzer_stats` and `plot_data` of the client up to date.
+//! LibAFL does not tell which mutation found an input, `op` is `havoc` or `init` for the seeds.
+//! The cycles come from the power schedule, the pending entries are the ones never scheduled yet.
+//! The confirmed timeouts go to `hangs/`, see [`crate::hangs`].
+use core::time::Duration;
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    env,
+    fmt::{self, Write as _},
+    fs::{self, OpenOptions},
+    io::Write,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    process,
+};
+
+use libafl::{
+    corpus::{Corpus, SchedulerTestcaseMetadata, Testcase},
+    events::EventFirer,
+    executors::ExitKind,
+    feedbacks::{Feedback, MapFeedbackMetadata},
+    observers::ObserversTuple,
+    schedulers::{minimizer::IsFavoredMetadata, powersched::SchedulerMetadata},
+    stages::Stage,
+    state::{HasCorpus, HasExecutions, HasSolutions, HasStartTime, State, UsesState},
+    Error, HasMetadata, HasNamedMetadata,
+};
+use libafl_bolts::{current_time, Named};
+
+use crate::hangs::HangsMetadata;
+
+/// How often `fuzzer_stats` and `plot_data` are written
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The window of `execs_ps_last_min`
+const SPEED_WINDOW: Duration = Duration::from_secs(60);
+
+/// Header of `plot_data`, the columns `afl-plot` expects
+const PLOT_HEADER: &str = "# relative_time, cycles_done, cur_item, corpus_count, pending_total, \
+    pending_favs, map_size, saved_crashes, saved_hangs, max_depth, execs_per_sec, total_execs, \
+    edges_found";
+
+/// The output directory of one client, created with its `queue/`, `crashes/` and `hangs/`
+#[derive(Debug, Clone)]
+pub struct AflOutput {
+    dir: PathBuf,
+    banner: &'static str,
+}
+
+impl AflOutput {
+    /// `<out>/<instance>/`, `banner` is the `afl_banner` of its `fuzzer_stats`
+    pub fn create(out: &Path, instance: &str, banner: &'static str) -> Result<Self, Error> {
+        let dir = out.join(instance);
+        for sub in ["queue", "crashes", "hangs"] {
+            fs::create_dir_all(dir.join(sub))?;
+        }
+        Ok(Self { dir, banner })
+    }
+
+    pub fn queue(&self) -> PathBuf {
+        self.dir.join("queue")
+    }
+
+    pub fn crashes(&self) -> PathBuf {
+        self.dir.join("crashes")
+    }
+
+    pub fn hangs(&self) -> PathBuf {
+        self.dir.join("hangs")
+    }
+}
+
+/// The AFL++ name of the entry `id` found now, e.g. `id:000123,src:000045,time:5120,execs:81234,op:havoc`
+pub fn afl_name<S>(state: &S, id: usize) -> String
+where
+    S: HasCorpus + HasExecutions + HasStartTime,
+{
+    let time = (current_time().saturating_sub(*state.start_time())).as_millis();
+    let execs = *state.executions();
+
+    let mut name = format!("id:{id:06}");
+    // The entry being fuzzed is the parent, there is none while loading the seeds
+    let op = match state.corpus().current() {
+        Some(parent) => {
+            write!(name, ",src:{:06}", parent.0).unwrap();
+            "havoc"
+        }
+        None => "init",
+    };
+    write!(name, ",time:{time},execs:{execs},op:{op}").unwrap();
+    name
+}
+
+/// Which corpus an [`AflNameFeedback`] names the entries of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AflCorpus {
+    Queue,
+    Crashes,
+}
+
+/// Gives new testcases their AFL++ file name, never interesting by itself.
+///
+/// Combine it with `feedback_or!`, the name is set in `append_metadata` before the
+/// corpus stores the testcase. Disabled, it leaves the default names alone.
+#[derive(Debug, Clone)]
+pub struct AflNameFeedback {
+    corpus: AflCorpus,
+    enabled: bool,
+}
+
+impl AflNameFeedback {
+    pub fn new(corpus: AflCorpus, enabled: bool) -> Self {
+        Self { corpus, enabled }
+    }
+}
+
+impl Named for AflNameFeedback {
+    fn name(&self) -> &Cow<'static, str> {
+        static QUEUE: Cow<'static, str> = Cow::Borrowed("afl_name_queue");
+        static CRASHES: Cow<'static, str> = Cow::Borrowed("afl_name_crashes");
+        match self.corpus {
+            AflCorpus::Queue => &QUEUE,
+            AflCorpus::Crashes => &CRASHES,
+        }
+    }
+}
+
+impl<S> Feedback<S> for AflNameFeedback
+where
+    S: State + HasCorpus + HasSolutions + HasExecutions + HasStartTime,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _input: &S::Input,
+        _observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<State = S>,
+        OT: ObserversTuple<S>,
+    {
+        Ok(false)
+    }
+
+    fn append_metadata<EM, OT>(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        _observers: &OT,
+        testcase: &mut Testcase<S::Input>,
+    ) -> Result<(), Error>
+    where
+        OT: ObserversTuple<S>,
+        EM: EventFirer<State = S>,
+    {
+        if !self.enabled {
+            return Ok(());
+        }
+        let id = match self.corpus {
+            AflCorpus::Queue => state.corpus().count(),
+            AflCorpus::Crashes => state.solutions().count(),
+        };
+        *testcase.filename_mut() = Some(afl_name(state, id));
+        Ok(())
+    }
+}
+
+/// The entries of the corpus not fuzzed yet and the deepest one
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct QueueStats {
+    pending_total: usize,
+    pending_favs: usize,
+    max_depth: u64,
+}
+
+impl QueueStats {
+    fn of<C: Corpus>(corpus: &C) -> Result<Self, Error> {
+        let mut stats = Self::default();
+        for id in corpus.ids() {
+            let testcase = corpus.get(id)?.borrow();
+            if testcase.scheduled_count() == 0 {
+                stats.pending_total += 1;
+                if testcase.has_metadata::<IsFavoredMetadata>() {
+                    stats.pending_favs += 1;
+                }
+            }
+            if let Ok(meta) = testcase.metadata::<SchedulerTestcaseMetadata>() {
+                stats.max_depth = stats.max_depth.max(meta.depth());
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// Writes `fuzzer_stats` and appends to `plot_data` every [`STATS_INTERVAL`]
+#[derive(Debug)]
+pub struct AflStatsStage<E, EM, Z> {
+    output: Option<AflOutput>,
+    last_write: Duration,
+    /// `(time, executions)` of the writes of the last [`SPEED_WINDOW`]
+    samples: VecDeque<(Duration, u64)>,
+    phantom: PhantomData<(E, EM, Z)>,
+}
+
+impl<E, EM, Z> AflStatsStage<E, EM, Z> {
+    /// A stage that does nothing without an output directory
+    pub fn new(output: Option<AflOutput>) -> Self {
+        Self {
+            output,
+            last_write: Duration::ZERO,
+            samples: VecDeque::new(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<E, EM, Z> UsesState for AflStatsStage<E, EM, Z>
+where
+    Z: UsesState,
+{
+    type State = Z::State;
+}
+
+impl<E, EM, Z> Stage<E, EM, Z> for AflStatsStage<E, EM, Z>
+where
+    E: UsesState<State = Z::State>,
+    EM: UsesState<State = Z::State>,
+    Z: UsesState,
+    Z::State:
+        HasCorpus + HasSolutions + HasExecutions + HasStartTime + HasMetadata + HasNamedMetadata,
+{
+    fn perform(
+        &mut self,
+        _fuzzer: &mut Z,
+        _executor: &mut E,
+        state: &mut Z::State,
+        _manager: &mut EM,
+    ) -> Result<(), Error> {
+        let Some(output) = &self.output else {
+            return Ok(());
+        };
+        let now = current_time();
+        if now.saturating_sub(self.last_write) < STATS_INTERVAL {
+            return Ok(());
+        }
+
+        let start = *state.start_time();
+        let run_time = now.saturating_sub(start);
+        let execs = *state.executions();
+        // Over the oldest write of the last minute, less right after the start
+        while self
+            .samples
+            .front()
+            .is_some_and(|(time, _)| now.saturating_sub(*time) > SPEED_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+        let current_speed = match self.samples.front() {
+            Some((time, then)) if now > *time => {
+                execs.saturating_sub(*then) as f64 / now.saturating_sub(*time).as_secs_f64()
+            }
+            _ => 0.0,
+        };
+        let average_speed = execs as f64 / run_time.as_secs_f64().max(1.0);
+        let corpus_count = state.corpus().count();
+        let crashes = state.solutions().count();
+        let hangs = state
+            .metadata_map()
+            .get::<HangsMetadata>()
+            .map_or(0, |hangs| hangs.count);
+        let current = state.corpus().current().map_or(0, |id| id.0);
+        let cycles = state
+            .metadata_map()
+            .get::<SchedulerMetadata>()
+            .map_or(0, SchedulerMetadata::queue_cycles);
+        let queue = QueueStats::of(state.corpus())?;
+        let (edges, map_len) = state
+            .named_metadata_map()
+            .get::<MapFeedbackMetadata<u8>>("edges")
+            .map_or((0, 0), |map| {
+                let edges = map.history_map.iter().filter(|&&hits| hits != 0).count();
+                (edges, map.history_map.len())
+            });
+        let map_density = if map_len == 0 {
+            0.0
+        } else {
+            edges as f64 * 100.0 / map_len as f64
+        };
+
+        let mut stats = String::new();
+        let mut field = |key: &str, value: &dyn fmt::Display| {
+            writeln!(stats, "{key:<18}: {value}").unwrap();
+        };
+        field("start_time", &start.as_secs());
+        field("last_update", &now.as_secs());
+        field("run_time", &run_time.as_secs());
+        field("fuzzer_pid", &process::id());
+        field("cycles_done", &cycles);
+        field("execs_done", &execs);
+        field("execs_per_sec", &format!("{average_speed:.2}"));
+        field("execs_ps_last_min", &format!("{current_speed:.2}"));
+        field("corpus_count", &corpus_count);
+        field("corpus_found", &corpus_count);
+        field("cur_item", &current);
+        field("pending_favs", &queue.pending_favs);
+        field("pending_total", &queue.pending_total);
+        field("max_depth", &queue.max_depth);
+        field("bitmap_cvg", &format!("{map_density:.2}%"));
+        field("edges_found", &edges);
+        field("saved_crashes", &crashes);
+        field("saved_hangs", &hangs);
+        field("afl_banner", &output.banner);
+        field("afl_version", &"libafl");
+        field("target_mode", &"default");
+        field("command_line", &env::args().collect::<Vec<_>>().join(" "));
+        // Written aside and renamed, the readers never see half a file
+        let stats_path = output.dir.join("fuzzer_stats");
+        let tmp_path = output.dir.join(".fuzzer_stats.tmp");
+        fs::write(&tmp_path, stats)?;
+        fs::rename(&tmp_path, &stats_path)?;
+
+        let plot_path = output.dir.join("plot_data");
+        let new_plot = !plot_path.exists();
+        let mut plot = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&plot_path)?;
+        if new_plot {
+            writeln!(plot, "{PLOT_HEADER}")?;
+        }
+        writeln!(
+            plot,
+            "{}, {cycles}, {current}, {corpus_count}, {}, {}, {map_density:.2}%, {crashes}, \
+             {hangs}, {}, {current_speed:.2}, {execs}, {edges}",
+            run_time.as_secs(),
+            queue.pending_total,
+            queue.pending_favs,
+            queue.max_depth
+        )?;
+
+        self.last_write = now;
+        self.samples.push_back((now, execs));
+        Ok(())
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
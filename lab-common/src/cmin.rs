@@ -0,0 +1,191 @@
+//! Corpus minimization (`--cmin`), like `afl-cmin`.
+//!
+//! Every input is executed once through the edges observer, and for each covered
+//! `(edge, hitcount bucket)` pair the smallest input covering it is kept.
+//! Each input runs in a forked child, as in `--replay`, which sends the map back through
+//! a pipe. Inputs that crash or hang the target are skipped with a warning, they belong to the
+//! crashes anyway.
+use core::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    fs::{self, File},
+    io::{self, Read, Write},
+    os::fd::FromRawFd,
+    path::{Path, PathBuf},
+};
+
+use libafl::{
+    corpus::InMemoryCorpus,
+    events::SimpleEventManager,
+    executors::{inprocess::InProcessExecutor, ExitKind, HasObservers},
+    feedbacks::ConstFeedback,
+    fuzzer::{ExecutesInput, StdFuzzer},
+    inputs::{BytesInput, HasTargetBytes},
+    monitors::SimpleMonitor,
+    observers::{HitcountsMapObserver, MapObserver, StdMapObserver},
+    schedulers::QueueScheduler,
+    state::StdState,
+    Error,
+};
+use libafl_bolts::{
+    current_time,
+    rands::StdRand,
+    tuples::{tuple_list, Handled},
+    AsSlice,
+};
+use libafl_targets::{libfuzzer_initialize, libfuzzer_test_one_input};
+
+/// The edges map of the harness, as the fuzzer observes it
+pub type EdgesObserver = HitcountsMapObserver<StdMapObserver<'static, u8, false>>;
+
+/// Copy the smallest subset of the inputs in `input_dirs` with the same coverage to `output_dir`,
+/// the coverage of each one read through the observer made by `edges_observer`
+pub fn minimize(
+    input_dirs: &[PathBuf],
+    output_dir: &Path,
+    timeout: Duration,
+    edges_observer: fn() -> EdgesObserver,
+) -> Result<(), Error> {
+    let mut files = Vec::new();
+    for dir in input_dirs {
+        collect_files(dir, &mut files)?;
+    }
+    // Smallest first, so the first input covering a pair is also the smallest one
+    files.sort_by_key(|(_, len)| *len);
+    println!("cmin: {} inputs in {:?}", files.len(), input_dirs);
+
+    let edges_observer = edges_observer();
+    let edges_handle = edges_observer.handle();
+
+    // Nothing is kept in the corpus, we only look at the map after each run
+    let mut feedback = ConstFeedback::new(false);
+    let mut objective = ConstFeedback::new(false);
+    let mut state = StdState::new(
+        StdRand::new(),
+        InMemoryCorpus::<BytesInput>::new(),
+        InMemoryCorpus::new(),
+        &mut feedback,
+        &mut objective,
+    )?;
+    let mut fuzzer = StdFuzzer::new(QueueScheduler::new(), feedback, objective);
+    let mut mgr = SimpleEventManager::new(SimpleMonitor::new(|s| println!("{s}")));
+
+    let mut harness = |input: &BytesInput| {
+        libfuzzer_test_one_input(input.target_bytes().as_slice());
+        ExitKind::Ok
+    };
+    let mut executor = InProcessExecutor::with_timeout(
+        &mut harness,
+        tuple_list!(edges_observer),
+        &mut fuzzer,
+        &mut state,
+        &mut mgr,
+        timeout,
+    )?;
+
+    let args: Vec<String> = env::args().collect();
+    if libfuzzer_initialize(&args) == -1 {
+        println!("Warning: LLVMFuzzerInitialize failed with -1");
+    }
+
+    // (edge, hitcount bucket) -> index of the smallest input covering it
+    let mut champions: HashMap<(usize, u8), usize> = HashMap::new();
+    let mut skipped = 0;
+    for (i, (path, _)) in files.iter().enumerate() {
+        let input = BytesInput::new(fs::read(path)?);
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let start = current_time();
+        match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error().into()),
+            0 => {
+                unsafe { libc::close(fds[0]) };
+                // A crash or timeout ends here, in the handlers of the executor
+                let exit_kind = fuzzer
+                    .execute_input(&mut state, &mut executor, &mut mgr, &input)
+                    .unwrap_or(ExitKind::Crash);
+                if exit_kind != ExitKind::Ok {
+                    unsafe { libc::_exit(1) }
+                }
+                let map = executor.observers()[&edges_handle].to_vec();
+                let mut pipe = unsafe { File::from_raw_fd(fds[1]) };
+                let written = pipe.write_all(&map);
+                unsafe { libc::_exit(i32::from(written.is_err())) }
+            }
+            pid => {
+                unsafe { libc::close(fds[1]) };
+                // The child blocks on a full pipe, so read before waiting
+                let mut map = Vec::new();
+                let read = unsafe { File::from_raw_fd(fds[0]) }.read_to_end(&mut map);
+                let mut status = 0;
+                unsafe { libc::waitpid(pid, &mut status, 0) };
+                let elapsed = current_time() - start;
+                if !(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0) || read.is_err() {
+                    skipped += 1;
+                    let reason = if elapsed >= timeout {
+                        format!("timeout after {} ms", elapsed.as_millis())
+                    } else if libc::WIFSIGNALED(status) {
+                        format!("crash, signal {}", libc::WTERMSIG(status))
+                    } else {
+                        format!("crash, exit code {}", libc::WEXITSTATUS(status))
+                    };
+                    println!("Warning: cmin: skipping {} ({reason})", path.display());
+                    continue;
+                }
+                for (edge, bucket) in map.into_iter().enumerate() {
+                    if bucket != 0 {
+                        champions.entry((edge, bucket)).or_insert(i);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut kept: Vec<usize> = champions.values().copied().collect();
+    kept.sort_unstable();
+    kept.dedup();
+
+    fs::create_dir_all(output_dir)?;
+    for &i in &kept {
+        let path = &files[i].0;
+        let name = path.file_name().unwrap().to_string_lossy();
+        let mut dest = output_dir.join(name.as_ref());
+        // Same name in two input dirs
+        if dest.exists() {
+            dest = output_dir.join(format!("{name}.{i}"));
+        }
+        fs::copy(path, dest)?;
+    }
+
+    let edges: HashSet<usize> = champions.keys().map(|(edge, _)| *edge).collect();
+    println!(
+        "cmin: kept {} inputs, dropped {} ({skipped} crashing or hanging), covering {} edges ({} edge/hitcount pairs), written to {:?}",
+        kept.len(),
+        files.len() - kept.len(),
+        edges.len(),
+        champions.len(),
+        output_dir
+    );
+    Ok(())
+}
+
+/// All files below `dir`, with their size, skipping the hidden `.metadata`/`.lafl_lock` files
+pub fn collect_files(dir: &Path, files: &mut Vec<(PathBuf, u64)>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect_files(&path, files)?;
+        } else if metadata.is_file() {
+            files.push((path, metadata.len()));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,363 @@
+//! Source-level coverage of a corpus (`--coverage`), with a `LIBAFL_COVERAGE=1` build of the fuzzer.
+//!
+//! The inputs are replayed like with `--replay`, every run writing its own profile, and
+//! `llvm-profdata`/`llvm-cov` (override with `LLVM_PROFDATA`/`LLVM_COV`) turn them into
+//! `coverage.lcov`, `html/index.html`, the per-file `report.txt` and the per-function
+//! `functions.txt` in the output directory. With `--coverage-base` a second corpus is
+//! measured too, and `diff.txt` lists the functions and lines only one of them reaches.
+//! Crashing inputs end before their profile is written, they are not counted.
+use core::time::Duration;
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use libafl::Error;
+
+use crate::{cmin::EdgesObserver, replay};
+
+/// Hit counts of the functions and lines of one source file
+#[derive(Debug, Default)]
+struct FileCoverage {
+    functions: BTreeMap<String, u64>,
+    lines: BTreeMap<u32, u64>,
+}
+
+/// Measure the coverage of `paths`, replayed with the edges map of `edges_observer`, and write
+/// the reports to `out`
+pub fn report(
+    paths: &[PathBuf],
+    base: Option<&Path>,
+    out: &Path,
+    timeout: Duration,
+    edges_observer: fn() -> EdgesObserver,
+) -> Result<(), Error> {
+    let binary = env::current_exe()?;
+    fs::create_dir_all(out)?;
+
+    let profile = measure(paths, &out.join("corpus"), timeout, edges_observer)?;
+    let lcov_path = out.join("coverage.lcov");
+    let lcov = run(llvm_cov("export")
+        .arg("-format=lcov")
+        .arg(&binary)
+        .arg(format!("-instr-profile={}", profile.display())))?;
+    fs::write(&lcov_path, &lcov)?;
+    run(llvm_cov("show")
+        .arg("-format=html")
+        .arg(format!("-output-dir={}", out.join("html").display()))
+        .arg(&binary)
+        .arg(format!("-instr-profile={}", profile.display())))?;
+    let summary = run(llvm_cov("report")
+        .arg(&binary)
+        .arg(format!("-instr-profile={}", profile.display())))?;
+    fs::write(out.join("report.txt"), &summary)?;
+    println!("{}", String::from_utf8_lossy(&summary));
+
+    let coverage = parse_lcov(&String::from_utf8_lossy(&lcov));
+    fs::write(out.join("functions.txt"), functions_report(&coverage))?;
+
+    if let Some(base) = base {
+        let base_profile = measure(
+            &[base.to_path_buf()],
+            &out.join("base"),
+            timeout,
+            edges_observer,
+        )?;
+        let base_lcov = run(llvm_cov("export")
+            .arg("-format=lcov")
+            .arg(&binary)
+            .arg(format!("-instr-profile={}", base_profile.display())))?;
+        fs::write(out.join("base.lcov"), &base_lcov)?;
+        let base_coverage = parse_lcov(&String::from_utf8_lossy(&base_lcov));
+
+        let mut diff = String::new();
+        diff_report(&mut diff, &format!("{paths:?}"), &coverage, &base_coverage);
+        diff_report(&mut diff, &format!("{base:?}"), &base_coverage, &coverage);
+        fs::write(out.join("diff.txt"), &diff)?;
+        print!("{diff}");
+    }
+
+    println!(
+        "coverage: {} written to {:?}",
+        lcov_path.display(),
+        out.join("html/index.html")
+    );
+    Ok(())
+}
+
+/// Replay `paths` into `<dir>/profraw/` and merge the profiles into `<dir>/coverage.profdata`
+fn measure(
+    paths: &[PathBuf],
+    dir: &Path,
+    timeout: Duration,
+    edges_observer: fn() -> EdgesObserver,
+) -> Result<PathBuf, Error> {
+    let raw_dir = dir.join("profraw");
+    // Leftovers of an earlier, bigger corpus would be merged as well
+    if raw_dir.exists() {
+        fs::remove_dir_all(&raw_dir)?;
+    }
+    replay::replay(paths, timeout, Some(&raw_dir), edges_observer)?;
+
+    let mut raw_files = Vec::new();
+    for entry in fs::read_dir(&raw_dir)? {
+        raw_files.push(entry?.path());
+    }
+    if raw_files.is_empty() {
+        return Err(Error::illegal_state(
+            "No profile written, is this a LIBAFL_COVERAGE=1 build of the fuzzer?",
+        ));
+    }
+
+    let profile = dir.join("coverage.profdata");
+    let mut merge =
+        Command::new(env::var("LLVM_PROFDATA").unwrap_or_else(|_| "llvm-profdata".into()));
+    merge
+        .arg("merge")
+        .arg("-sparse")
+        .args(&raw_files)
+        .arg("-o")
+        .arg(&profile);
+    run(&mut merge)?;
+    Ok(profile)
+}
+
+fn llvm_cov(subcommand: &str) -> Command {
+    let mut cmd = Command::new(env::var("LLVM_COV").unwrap_or_else(|_| "llvm-cov".into()));
+    cmd.arg(subcommand);
+    cmd
+}
+
+/// Run a tool, returning its stdout
+fn run(cmd: &mut Command) -> Result<Vec<u8>, Error> {
+    let output = cmd.output().map_err(|err| {
+        Error::illegal_state(format!("Failed to run {:?}: {err}", cmd.get_program()))
+    })?;
+    if !output.status.success() {
+        return Err(Error::illegal_state(format!(
+            "{cmd:?} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/// The functions and lines of an lcov tracefile, by source file
+fn parse_lcov(lcov: &str) -> BTreeMap<String, FileCoverage> {
+    let mut files = BTreeMap::new();
+    let mut current: Option<(String, FileCoverage)> = None;
+    for line in lcov.lines() {
+        if let Some(file) = line.strip_prefix("SF:") {
+            current = Some((file.to_string(), FileCoverage::default()));
+        } else if line == "end_of_record" {
+            if let Some((file, coverage)) = current.take() {
+                files.insert(file, coverage);
+            }
+        } else if let Some((_, coverage)) = current.as_mut() {
+            if let Some(function) = line.strip_prefix("FN:") {
+                if let Some((_, name)) = function.split_once(',') {
+                    coverage.functions.entry(name.to_string()).or_insert(0);
+                }
+            } else if let Some(hits) = line.strip_prefix("FNDA:") {
+                if let Some((count, name)) = hits.split_once(',') {
+                    coverage
+                        .functions
+                        .insert(name.to_string(), count.parse().unwrap_or(0));
+                }
+            } else if let Some(hits) = line.strip_prefix("DA:") {
+                let mut fields = hits.split(',');
+                if let (Some(number), Some(count)) = (fields.next(), fields.next()) {
+                    if let (Ok(number), Ok(count)) = (number.parse(), count.parse()) {
+                        coverage.lines.insert(number, count);
+                    }
+                }
+            }
+        }
+    }
+    files
+}
+
+/// Every function with its hit count, the unreached ones first
+fn functions_report(files: &BTreeMap<String, FileCoverage>) -> String {
+    let mut report = String::new();
+    for (file, coverage) in files {
+        let reached = coverage
+            .functions
+            .values()
+            .filter(|&&hits| hits > 0)
+            .count();
+        writeln!(
+            report,
+            "{file}: {reached}/{} functions",
+            coverage.functions.len()
+        )
+        .unwrap();
+        let mut functions: Vec<_> = coverage.functions.iter().collect();
+        functions.sort_by_key(|(name, hits)| (**hits > 0, name.as_str()));
+        for (name, hits) in functions {
+            writeln!(report, "    {hits:>10}  {name}").unwrap();
+        }
+    }
+    report
+}
+
+/// What `ours` reaches and `theirs` does not
+fn diff_report(
+    report: &mut String,
+    label: &str,
+    ours: &BTreeMap<String, FileCoverage>,
+    theirs: &BTreeMap<String, FileCoverage>,
+) {
+    let empty = FileCoverage::default();
+    writeln!(report, "Only reached by {label}:").unwrap();
+    for (file, coverage) in ours {
+        let other = theirs.get(file).unwrap_or(&empty);
+        let functions: Vec<&String> = coverage
+            .functions
+            .iter()
+            .filter(|(name, &hits)| {
+                hits > 0 && other.functions.get(*name).copied().unwrap_or(0) == 0
+            })
+            .map(|(name, _)| name)
+            .collect();
+        let lines: Vec<u32> = coverage
+            .lines
+            .iter()
+            .filter(|(number, &hits)| {
+                hits > 0 && other.lines.get(*number).copied().unwrap_or(0) == 0
+            })
+            .map(|(number, _)| *number)
+            .collect();
+        if functions.is_empty() && lines.is_empty() {
+            continue;
+        }
+
+        writeln!(
+            report,
+            "  {file}: {} functions, {} lines",
+            functions.len(),
+            lines.len()
+        )
+        .unwrap();
+        for function in functions {
+            writeln!(report, "    fn {function}").unwrap();
+        }
+        if !lines.is_empty() {
+            writeln!(report, "    lines {}", line_ranges(&lines)).unwrap();
+        }
+    }
+}
+
+/// `1, 2, 3, 7` as `1-3, 7`
+fn line_ranges(lines: &[u32]) -> String {
+    let mut ranges = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let start = lines[i];
+        while i + 1 < lines.len() && lines[i + 1] == lines[i] + 1 {
+            i += 1;
+        }
+        ranges.push(if lines[i] == start {
+            start.to_string()
+        } else {
+            format!("{start}-{}", lines[i])
+        });
+        i += 1;
+    }
+    ranges.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_report, functions_report, line_ranges, parse_lcov};
+
+    const CORPUS: &str = "\
+TN:
+SF:/src/png.c
+FN:10,png_read_info
+FN:40,png_read_row
+FNDA:3,png_read_info
+FNDA:0,png_read_row
+DA:11,3
+DA:12,3
+DA:13,3,a1b2c3
+DA:17,1
+DA:41,0
+LF:5
+LH:4
+end_of_record
+SF:/src/harness.c
+FN:5,LLVMFuzzerTestOneInput
+FNDA:3,LLVMFuzzerTestOneInput
+DA:6,3
+end_of_record
+";
+
+    const BASE: &str = "\
+SF:/src/png.c
+FN:10,png_read_info
+FN:40,png_read_row
+FNDA:1,png_read_info
+FNDA:0,png_read_row
+DA:11,1
+DA:12,0
+end_of_record
+";
+
+    #[test]
+    fn lcov_functions_and_lines() {
+        let files = parse_lcov(CORPUS);
+        assert_eq!(files.len(), 2);
+        let png = &files["/src/png.c"];
+        assert_eq!(png.functions["png_read_info"], 3);
+        assert_eq!(png.functions["png_read_row"], 0);
+        assert_eq!(
+            png.lines.iter().map(|(&n, &h)| (n, h)).collect::<Vec<_>>(),
+            [(11, 3), (12, 3), (13, 3), (17, 1), (41, 0)]
+        );
+        assert_eq!(
+            files["/src/harness.c"].functions["LLVMFuzzerTestOneInput"],
+            3
+        );
+    }
+
+    #[test]
+    fn unreached_functions_first() {
+        let report = functions_report(&parse_lcov(BASE));
+        assert_eq!(
+            report,
+            "/src/png.c: 1/2 functions\n             0  png_read_row\n             1  png_read_info\n"
+        );
+    }
+
+    #[test]
+    fn diff_of_two_corpora() {
+        let corpus = parse_lcov(CORPUS);
+        let base = parse_lcov(BASE);
+        let mut diff = String::new();
+        diff_report(&mut diff, "corpus", &corpus, &base);
+        diff_report(&mut diff, "base", &base, &corpus);
+        assert_eq!(
+            diff,
+            "Only reached by corpus:
+  /src/harness.c: 1 functions, 1 lines
+    fn LLVMFuzzerTestOneInput
+    lines 6
+  /src/png.c: 0 functions, 3 lines
+    lines 12-13, 17
+Only reached by base:
+"
+        );
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(line_ranges(&[1, 2, 3, 7]), "1-3, 7");
+        assert_eq!(line_ranges(&[4, 6, 7]), "4, 6-7");
+        assert_eq!(line_ranges(&[]), "");
+    }
+}
@@ -0,0 +1,417 @@
+//! Crash deduplication by stack hash, in the style of `BacktraceObserver` + `NewHashFeedback`.
+//!
+//! The observer hashes the symbols of the top `--crash-depth` frames of the target when it
+//! crashes, the feedback only lets a crash through as a solution if its hash is new.
+//! The hash and the frames are stored in the metadata of the solution.
+//! The hashes seen so far are files in a [`HASHES_DIR`] next to the crashes, shared by all
+//! clients of a campaign and kept across runs: a crash one client found is not saved again by
+//! another. Their number is reported to the monitor as `unique crashes`.
+//! An ASan report aborts the target, the `__asan`/`__sanitizer` frames are skipped.
+//! Without symbols (stripped build) no hash can be computed and every crash is kept.
+//! With `--forkserver` the target collects its frames itself and hands them over in a
+//! [`ChildCrashInfo`].
+use std::{
+    borrow::Cow,
+    collections::hash_map::DefaultHasher,
+    fs::{self, OpenOptions},
+    hash::{Hash, Hasher},
+    io::ErrorKind,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+use backtrace::Backtrace;
+use libafl::{
+    corpus::Testcase,
+    events::{Event, EventFirer},
+    executors::ExitKind,
+    feedbacks::Feedback,
+    inputs::UsesInput,
+    monitors::{AggregatorOps, UserStats, UserStatsValue},
+    observers::{Observer, ObserverWithHashField, ObserversTuple},
+    state::State,
+    Error, HasMetadata,
+};
+use libafl_bolts::{
+    tuples::{Handle, Handled, MatchNameRef},
+    Named, SerdeAny,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::forkserver::ChildCrashInfo;
+
+/// Default number of frames that make up the hash
+pub const DEFAULT_DEPTH: usize = 5;
+
+/// One empty file per stack hash seen, hidden in the crashes directory
+pub const HASHES_DIR: &str = ".hashes";
+
+/// Frames of the signal handler and libc, the same for every crash
+const RUNTIME_FRAMES: &[&str] = &[
+    "??",
+    "__restore_rt",
+    "__kernel_rt_sigreturn",
+    "raise",
+    "abort",
+    "gsignal",
+    "pthread_kill",
+    "__pthread_kill",
+];
+
+/// Prefixes of the frames of glibc internals and the sanitizer runtimes
+const RUNTIME_PREFIXES: &[&str] = &[
+    "__GI_",
+    "__sanitizer",
+    "__asan",
+    "__ubsan",
+    "__msan",
+    "__interceptor_",
+];
+
+/// Hashes the top frames of the target when it crashes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StackHashObserver {
+    name: Cow<'static, str>,
+    depth: usize,
+    hash: Option<u64>,
+    frames: Vec<String>,
+    #[serde(skip)]
+    child: Option<ChildCrashInfo>,
+}
+
+impl StackHashObserver {
+    pub fn new(name: &'static str, depth: usize) -> Self {
+        Self {
+            name: Cow::Borrowed(name),
+            depth,
+            hash: None,
+            frames: Vec::new(),
+            child: None,
+        }
+    }
+
+    /// The frames of the last crash, innermost first
+    pub fn frames(&self) -> &[String] {
+        &self.frames
+    }
+
+    /// Reads the crashes of a forkserver target from `child` instead of the own stack
+    pub fn with_child(mut self, child: ChildCrashInfo) -> Self {
+        self.child = Some(child);
+        self
+    }
+
+    fn set_frames(&mut self, frames: Vec<String>) {
+        self.frames = frames.into_iter().take(self.depth).collect();
+        self.hash = stack_hash(&self.frames);
+    }
+}
+
+/// The hash of the top frames of a crash, `None` without any (no symbols)
+pub fn stack_hash(frames: &[String]) -> Option<u64> {
+    if frames.is_empty() {
+        return None;
+    }
+    let mut hasher = DefaultHasher::new();
+    frames.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// The frames of the target on the current stack, innermost first: below the frame of the
+/// signal handler whose name contains `handler`, up to the entry of the harness
+pub fn crash_frames(handler: &str) -> Vec<String> {
+    let backtrace = Backtrace::new();
+    let names: Vec<String> = backtrace
+        .frames()
+        .iter()
+        .flat_map(|frame| frame.symbols())
+        .map(|symbol| {
+            symbol
+                .name()
+                .map_or_else(|| "??".into(), |name| format!("{name:#}"))
+        })
+        .collect();
+    target_frames(&names, handler)
+}
+
+/// The frames of `names` (a whole stack, innermost first) below the one containing `handler`
+/// and up to the entry of the harness, without the runtime frames
+pub fn target_frames(names: &[String], handler: &str) -> Vec<String> {
+    let start = names
+        .iter()
+        .rposition(|name| name.contains(handler))
+        .map_or(0, |i| i + 1);
+    let end = names
+        .iter()
+        .position(|name| name == "LLVMFuzzerTestOneInput")
+        .map_or(names.len(), |i| i + 1)
+        .max(start);
+
+    names[start..end]
+        .iter()
+        .filter(|name| !is_runtime_frame(name))
+        .cloned()
+        .collect()
+}
+
+pub fn is_runtime_frame(name: &str) -> bool {
+    RUNTIME_FRAMES.contains(&name)
+        || RUNTIME_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
+impl Named for StackHashObserver {
+    fn name(&self) -> &Cow<'static, str> {
+        &self.name
+    }
+}
+
+impl ObserverWithHashField for StackHashObserver {
+    fn hash(&self) -> Option<u64> {
+        self.hash
+    }
+}
+
+impl<S> Observer<S> for StackHashObserver
+where
+    S: UsesInput,
+{
+    fn pre_exec(&mut self, _state: &mut S, _input: &S::Input) -> Result<(), Error> {
+        self.hash = None;
+        self.frames.clear();
+        if let Some(child) = &self.child {
+            child.clear();
+        }
+        Ok(())
+    }
+
+    // In-process: called from the crash handler of the executor, still on the crashing stack
+    fn post_exec(
+        &mut self,
+        _state: &mut S,
+        _input: &S::Input,
+        exit_kind: &ExitKind,
+    ) -> Result<(), Error> {
+        if *exit_kind == ExitKind::Crash {
+            let frames = match &self.child {
+                Some(child) => child.frames(),
+                None => crash_frames("unix_signals"),
+            };
+            self.set_frames(frames);
+        }
+        Ok(())
+    }
+}
+
+/// The stack hash of a solution
+#[derive(Debug, Clone, Serialize, Deserialize, SerdeAny)]
+pub struct CrashHashMetadata {
+    pub hash: u64,
+    pub frames: Vec<String>,
+}
+
+/// Only interesting for a crash with a new stack hash, or for one without a hash
+/// (e.g. timeouts, or no `StackHashObserver` at all)
+#[derive(Debug, Clone)]
+pub struct UniqueCrashFeedback {
+    observer_handle: Handle<StackHashObserver>,
+    hashes_dir: PathBuf,
+}
+
+impl UniqueCrashFeedback {
+    /// With the hashes in `<dir>/`[`HASHES_DIR`], `dir` is the same for every client of the
+    /// campaign (its crashes directory, or the output directory above the ones of the clients)
+    pub fn new(observer: &StackHashObserver, dir: &Path) -> Result<Self, Error> {
+        let hashes_dir = dir.join(HASHES_DIR);
+        fs::create_dir_all(&hashes_dir)?;
+        Ok(Self {
+            observer_handle: observer.handle(),
+            hashes_dir,
+        })
+    }
+}
+
+impl Named for UniqueCrashFeedback {
+    fn name(&self) -> &Cow<'static, str> {
+        self.observer_handle.name()
+    }
+}
+
+impl<S> Feedback<S> for UniqueCrashFeedback
+where
+    S: State,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        state: &mut S,
+        manager: &mut EM,
+        _input: &S::Input,
+        observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<State = S>,
+        OT: ObserversTuple<S>,
+    {
+        // Without the observer there is no hash either
+        let Some(hash) = observers
+            .get(&self.observer_handle)
+            .and_then(|observer| observer.hash())
+        else {
+            return Ok(true);
+        };
+
+        // Created atomically: of two clients with the same crash only one gets the file
+        let created = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.hashes_dir.join(format!("{hash:016x}")));
+        match created {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => return Ok(false),
+            Err(err) => return Err(err.into()),
+        }
+        // The count of the whole campaign, the same for every client
+        let unique = fs::read_dir(&self.hashes_dir)?.count() as u64;
+        manager.fire(
+            state,
+            Event::UpdateUserStats {
+                name: Cow::Borrowed("unique crashes"),
+                value: UserStats::new(UserStatsValue::Number(unique), AggregatorOps::Max),
+                phantom: PhantomData,
+            },
+        )?;
+        Ok(true)
+    }
+
+    fn append_metadata<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        observers: &OT,
+        testcase: &mut Testcase<S::Input>,
+    ) -> Result<(), Error>
+    where
+        OT: ObserversTuple<S>,
+        EM: EventFirer<State = S>,
+    {
+        if let Some(observer) = observers.get(&self.observer_handle) {
+            if let Some(hash) = observer.hash() {
+                testcase.add_metadata(CrashHashMetadata {
+                    hash,
+                    frames: observer.frames().to_vec(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use libafl::{
+        corpus::InMemoryCorpus,
+        events::SimpleEventManager,
+        executors::ExitKind,
+        feedbacks::{ConstFeedback, Feedback},
+        inputs::BytesInput,
+        monitors::NopMonitor,
+        state::StdState,
+    };
+    use libafl_bolts::{rands::StdRand, tuples::tuple_list};
+
+    use super::{stack_hash, target_frames, StackHashObserver, UniqueCrashFeedback};
+
+    fn strings(names: &[&str]) -> Vec<String> {
+        names.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn frames_between_the_handler_and_the_harness() {
+        let stack = strings(&[
+            "backtrace::backtrace::trace",
+            "lab_common::dedup::crash_frames",
+            "libafl::executors::hooks::unix::unix_signal_handler::inproc_crash_handler",
+            "libafl_bolts::os::unix_signals::handle_signal",
+            "__restore_rt",
+            "raise",
+            "abort",
+            "__asan::ReportGenericError",
+            "__interceptor_memcpy",
+            "png_read_row",
+            "read_png",
+            "LLVMFuzzerTestOneInput",
+            "fuzzer_libpng::libafl_main::{{closure}}",
+            "main",
+        ]);
+        assert_eq!(
+            target_frames(&stack, "unix_signals"),
+            ["png_read_row", "read_png", "LLVMFuzzerTestOneInput"]
+        );
+        // No handler frame, from the top
+        assert_eq!(
+            target_frames(&stack[9..], "unix_signals"),
+            ["png_read_row", "read_png", "LLVMFuzzerTestOneInput"]
+        );
+    }
+
+    #[test]
+    fn hash_of_the_top_frames() {
+        assert_eq!(stack_hash(&[]), None);
+
+        let mut first = StackHashObserver::new("stack", 2);
+        first.set_frames(strings(&["png_read_row", "read_png", "caller_a"]));
+        let mut second = StackHashObserver::new("stack", 2);
+        second.set_frames(strings(&["png_read_row", "read_png", "caller_b"]));
+        assert_eq!(first.frames(), ["png_read_row", "read_png"]);
+        assert!(first.hash.is_some());
+        assert_eq!(first.hash, second.hash);
+
+        second.set_frames(strings(&["png_read_row", "png_read_info"]));
+        assert_ne!(first.hash, second.hash);
+    }
+
+    #[test]
+    fn only_new_hashes_are_interesting() {
+        let dir = env::temp_dir().join(format!("dedup-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut state = StdState::new(
+            StdRand::with_seed(0),
+            InMemoryCorpus::<BytesInput>::new(),
+            InMemoryCorpus::new(),
+            &mut ConstFeedback::new(false),
+            &mut ConstFeedback::new(false),
+        )
+        .unwrap();
+        let mut mgr = SimpleEventManager::new(NopMonitor::new());
+        let input = BytesInput::new(b"crash".to_vec());
+
+        let mut observer = StackHashObserver::new("stack", 5);
+        // The hashes directory is shared, like by two clients of a campaign
+        let mut feedback = UniqueCrashFeedback::new(&observer, &dir).unwrap();
+        let mut other = UniqueCrashFeedback::new(&observer, &dir).unwrap();
+        let mut interesting = |feedback: &mut UniqueCrashFeedback, observer: &StackHashObserver| {
+            let observers = tuple_list!(observer.clone());
+            feedback
+                .is_interesting(&mut state, &mut mgr, &input, &observers, &ExitKind::Crash)
+                .unwrap()
+        };
+
+        // No hash, e.g. a stripped build: always kept
+        assert!(interesting(&mut feedback, &observer));
+        assert!(interesting(&mut feedback, &observer));
+
+        observer.set_frames(strings(&["png_read_row", "read_png"]));
+        assert!(interesting(&mut feedback, &observer));
+        assert!(!interesting(&mut feedback, &observer));
+        assert!(!interesting(&mut other, &observer));
+
+        observer.set_frames(strings(&["png_read_info", "read_png"]));
+        assert!(interesting(&mut other, &observer));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
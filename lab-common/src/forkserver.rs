@@ -0,0 +1,366 @@
+//! Forkserver mode (`--forkserver`): the target runs out of process, in a fresh fork per input.
+//!
+//! The forkserver build of the wrapper (the `forkserver` feature) links the same fuzzer binary
+//! with the forkserver runtime of `libafl_targets`, so it is its own target: started with
+//! [`TARGET_ENV`] set, [`run_target`] maps the coverage map and the input from the shared memory
+//! of the `ForkserverExecutor` and forks for every run. Leaks and corrupted global state of the
+//! target end with each child. With `pointer_maps` the edges of the target go to the shared map,
+//! not to the static `EDGES_MAP` of the child.
+//!
+//! What the in-process executor reads from the crashing process comes back through shared
+//! memory too: the child writes the frames and the sanitizer report of a crash to
+//! [`ChildCrashInfo`], and the comparisons of the tracing forkserver of `--cmplog` to a
+//! [`cmplog_map`]. A sanitizer report is written by the death callback of the runtime, where
+//! the child can still allocate. Any other crash only gets its raw frames, from a signal handler
+//! that neither allocates nor locks (`backtrace` and `dladdr` into the shared memory, the
+//! forkserver builds link with `-rdynamic` for the names); the executor demangles them.
+//!
+//! Without [`TARGET_ENV`] set to a forkserver (e.g. for [`crate::hangs`]), [`run_target`] runs
+//! the input from stdin once.
+use std::{
+    env,
+    io::{self, Read},
+    mem, process, slice,
+};
+
+use backtrace::SymbolName;
+
+use libafl::{feedbacks::MapFeedbackMetadata, Error, HasNamedMetadata};
+use libafl_bolts::{
+    shmem::{ShMem, ShMemProvider},
+    AsSliceMut,
+};
+use libafl_targets::{libfuzzer_initialize, libfuzzer_test_one_input, CmpLogMap};
+#[cfg(feature = "forkserver")]
+use {
+    crate::sanitizers,
+    libafl_bolts::shmem::{ShMemId, UnixShMemProvider},
+    libafl_targets::{
+        map_input_shared_memory, map_shared_memory, start_forkserver, CMPLOG_ENABLED,
+        CMPLOG_MAP_PTR,
+    },
+    std::{
+        ffi::{c_int, c_void, CStr},
+        ptr,
+        sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+    },
+};
+
+use crate::dedup;
+
+/// Set in the environment of the target started by the executor
+pub const TARGET_ENV: &str = "LIBAFL_FORKSERVER_TARGET";
+
+/// `<id>:<size>` of the [`ChildCrashInfo`] of the target
+pub const CRASH_SHM_ENV: &str = "LIBAFL_CRASH_SHM";
+
+/// `<id>:<size>` of the [`cmplog_map`] of the tracing target
+pub const CMPLOG_SHM_ENV: &str = "LIBAFL_CMPLOG_SHM";
+
+/// Size of the coverage map shared with the target
+pub const MAP_SIZE: usize = 65536;
+
+/// Room for the frames and the sanitizer report of one crash
+const CRASH_INFO_SIZE: usize = 1 << 20;
+
+/// Frames taken by the signal handler of the child
+#[cfg(feature = "forkserver")]
+const MAX_FRAMES: usize = 128;
+
+/// Tells the forkserver runtime that we read the input from shared memory
+#[cfg(feature = "forkserver")]
+#[no_mangle]
+pub static __afl_sharedmem_fuzzing: c_int = 1;
+
+#[cfg(feature = "forkserver")]
+extern "C" {
+    static __afl_fuzz_ptr: *mut u8;
+    static __afl_fuzz_len: *mut u32;
+}
+
+/// Fails unless this is the forkserver build, `--forkserver` needs the runtime in the target
+pub fn check_build() -> Result<(), Error> {
+    if cfg!(feature = "forkserver") {
+        Ok(())
+    } else {
+        Err(Error::illegal_argument(
+            "--forkserver needs the forkserver build of the fuzzer (the `forkserver` feature, see the forkserver task of the Makefile.toml)",
+        ))
+    }
+}
+
+/// The coverage map of the target, its id in `__AFL_SHM_ID` for the forkserver runtime
+pub fn coverage_map<SP: ShMemProvider>(provider: &mut SP) -> Result<SP::ShMem, Error> {
+    let mut shmem = provider.new_shmem(MAP_SIZE)?;
+    shmem.write_to_env("__AFL_SHM_ID")?;
+    Ok(shmem)
+}
+
+/// The map the tracing target logs its comparisons to, for `CmpLogObserver::with_map_ptr`;
+/// hand it to the tracing executor only, as [`CMPLOG_SHM_ENV`] = [`shmem_env`]
+pub fn cmplog_map<SP: ShMemProvider>(provider: &mut SP) -> Result<SP::ShMem, Error> {
+    Ok(provider.new_shmem(mem::size_of::<CmpLogMap>())?)
+}
+
+/// The memory for a [`ChildCrashInfo`], as [`CRASH_SHM_ENV`] = [`shmem_env`] for the target
+pub fn crash_info_map<SP: ShMemProvider>(provider: &mut SP) -> Result<SP::ShMem, Error> {
+    Ok(provider.new_shmem(CRASH_INFO_SIZE)?)
+}
+
+/// `<id>:<size>` of `shmem`, for the environment of one executor
+pub fn shmem_env<S: ShMem>(shmem: &S) -> String {
+    format!("{}:{}", shmem.id().as_str(), shmem.len())
+}
+
+/// Fails if the inputs run so far reached no edge at all: the target does not write to the
+/// shared coverage map
+pub fn check_coverage<S: HasNamedMetadata>(state: &S) -> Result<(), Error> {
+    let edges = state
+        .named_metadata_map()
+        .get::<MapFeedbackMetadata<u8>>("edges")
+        .map_or(0, |map| {
+            map.history_map.iter().filter(|&&hits| hits != 0).count()
+        });
+    if edges == 0 {
+        return Err(Error::illegal_state(
+            "The forkserver target reported no edges for the initial inputs, is the harness instrumented and linked by the forkserver build of the wrapper?",
+        ));
+    }
+    println!("Forkserver coverage: {edges} edges from the initial inputs");
+    Ok(())
+}
+
+/// The frames and the sanitizer report of a crash of the target, in shared memory.
+///
+/// Layout: the length as `u32` LE, then the frames one per line, an empty line and the report.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChildCrashInfo {
+    // Address and size of the mapping, plain numbers so the observers stay `Send` + `Default`
+    addr: usize,
+    size: usize,
+}
+
+impl ChildCrashInfo {
+    /// Over a [`crash_info_map`], which has to outlive it
+    pub fn new<S: ShMem>(shmem: &mut S) -> Self {
+        let memory = shmem.as_slice_mut();
+        Self {
+            addr: memory.as_mut_ptr() as usize,
+            size: memory.len(),
+        }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn memory(&self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.addr as *mut u8, self.size) }
+    }
+
+    /// Before every run
+    pub fn clear(&self) {
+        self.memory()[..4].fill(0);
+    }
+
+    /// The frames of the target in the last crash, innermost first
+    pub fn frames(&self) -> Vec<String> {
+        let text = self.text();
+        let frames = text
+            .split_once("\n\n")
+            .map_or(text.as_str(), |(frames, _)| frames);
+        // The signal handler leaves the symbols mangled and the runtime frames in
+        let names: Vec<String> = frames
+            .lines()
+            .filter(|name| !name.is_empty())
+            .map(|name| format!("{:#}", SymbolName::new(name.as_bytes())))
+            .collect();
+        dedup::target_frames(&names, "crash_handler")
+    }
+
+    /// The sanitizer report of the last crash
+    pub fn report(&self) -> Option<String> {
+        let text = self.text();
+        let (_, report) = text.split_once("\n\n")?;
+        (!report.is_empty()).then(|| report.to_string())
+    }
+
+    fn text(&self) -> String {
+        let memory = self.memory();
+        let len = u32::from_le_bytes(memory[..4].try_into().unwrap()) as usize;
+        let len = len.min(memory.len() - 4);
+        String::from_utf8_lossy(&memory[4..4 + len]).into_owned()
+    }
+
+    /// In the death callback of the sanitizer, before it aborts the child
+    #[cfg(feature = "forkserver")]
+    fn write(&self, frames: &[String], report: Option<&str>) {
+        let memory = self.memory();
+        let mut text = frames.join("\n");
+        text.push_str("\n\n");
+        text.push_str(report.unwrap_or_default());
+        // A report too long for the map is cut off, the frames come first
+        let len = text.len().min(memory.len() - 4);
+        memory[4..4 + len].copy_from_slice(&text.as_bytes()[..len]);
+        memory[..4].copy_from_slice(&(len as u32).to_le_bytes());
+    }
+
+    /// In the signal handler: the symbol of every frame, without allocating or locking
+    #[cfg(feature = "forkserver")]
+    unsafe fn write_stack(&self) {
+        let memory = self.memory();
+        let stack = &mut *ptr::addr_of_mut!(STACK);
+        let count = libc::backtrace(stack.as_mut_ptr(), MAX_FRAMES as c_int).max(0) as usize;
+        let mut len = 0;
+        for &address in &stack[..count] {
+            let mut info: libc::Dl_info = mem::zeroed();
+            let name = if libc::dladdr(address, &mut info) != 0 && !info.dli_sname.is_null() {
+                CStr::from_ptr(info.dli_sname).to_bytes()
+            } else {
+                b"??"
+            };
+            // The name, its newline and the empty line that ends the frames
+            if 4 + len + name.len() + 2 > memory.len() {
+                break;
+            }
+            memory[4 + len..4 + len + name.len()].copy_from_slice(name);
+            memory[4 + len + name.len()] = b'\n';
+            len += name.len() + 1;
+        }
+        memory[4 + len] = b'\n';
+        len += 1;
+        memory[..4].copy_from_slice(&(len as u32).to_le_bytes());
+    }
+}
+
+/// The crash info of the child, set up before the forkserver starts
+#[cfg(feature = "forkserver")]
+static CRASH_INFO: AtomicPtr<ChildCrashInfo> = AtomicPtr::new(ptr::null_mut());
+
+/// Set by the death callback, the signal handler then leaves the crash info alone
+#[cfg(feature = "forkserver")]
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// The return addresses of the crashing stack, allocated before any crash
+#[cfg(feature = "forkserver")]
+static mut STACK: [*mut c_void; MAX_FRAMES] = [ptr::null_mut(); MAX_FRAMES];
+
+/// Maps the shared memory in `env_name` of the executor for the life of the target
+#[cfg(feature = "forkserver")]
+fn map_env_shmem(env_name: &str) -> Option<&'static mut [u8]> {
+    let value = env::var(env_name).ok()?;
+    let (id, size) = value.split_once(':')?;
+    let size: usize = size.parse().ok()?;
+    let mut shmem = UnixShMemProvider::new()
+        .and_then(|mut provider| provider.shmem_from_id_and_size(ShMemId::from_string(id), size))
+        .expect("Failed to map the shared memory of the executor");
+    let memory = unsafe { slice::from_raw_parts_mut(shmem.as_slice_mut().as_mut_ptr(), size) };
+    mem::forget(shmem);
+    Some(memory)
+}
+
+/// Called by the sanitizer runtime before it aborts, not from a signal handler: the frames
+/// are symbolized and the report is taken here
+#[cfg(feature = "forkserver")]
+extern "C" fn death_callback() {
+    let info = CRASH_INFO.load(Ordering::SeqCst);
+    if !info.is_null() {
+        let frames = dedup::crash_frames("death_callback");
+        let report = sanitizers::take_last_report();
+        unsafe { (*info).write(&frames, report.as_deref()) };
+        REPORTED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Writes the frames of the crash for the executor, unless the sanitizer already did, then dies
+/// of `signal`. Exported so `dladdr` finds its name, the frames of the target are below it
+#[cfg(feature = "forkserver")]
+#[no_mangle]
+extern "C" fn lab_common_crash_handler(signal: c_int) {
+    let info = CRASH_INFO.load(Ordering::SeqCst);
+    if !info.is_null() && !REPORTED.load(Ordering::SeqCst) {
+        unsafe { (*info).write_stack() };
+    }
+    // The handler was reset on entry (`SA_RESETHAND`), this ends the child
+    unsafe { libc::raise(signal) };
+}
+
+#[cfg(feature = "forkserver")]
+fn install_crash_handler(info: ChildCrashInfo) {
+    CRASH_INFO.store(Box::into_raw(Box::new(info)), Ordering::SeqCst);
+    sanitizers::register_report_callback();
+    sanitizers::register_death_callback(death_callback);
+    // The first `backtrace` loads libgcc_s, which allocates: done here, in the forkserver
+    unsafe { libc::backtrace(ptr::addr_of_mut!(STACK).cast(), 1) };
+    for signal in [
+        libc::SIGABRT,
+        libc::SIGSEGV,
+        libc::SIGBUS,
+        libc::SIGILL,
+        libc::SIGFPE,
+    ] {
+        unsafe {
+            // A sanitizer that handles the signal itself reports first and then aborts
+            let mut old: libc::sigaction = mem::zeroed();
+            libc::sigaction(signal, ptr::null(), &mut old);
+            if signal != libc::SIGABRT && old.sa_sigaction != libc::SIG_DFL {
+                continue;
+            }
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction =
+                lab_common_crash_handler as extern "C" fn(c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESETHAND;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, ptr::null_mut());
+        }
+    }
+}
+
+/// Maps what the executor shares and starts the forkserver, returns once in every child
+#[cfg(feature = "forkserver")]
+fn start() {
+    if env::var_os("__AFL_SHM_ID").is_none() {
+        return;
+    }
+    map_shared_memory();
+    map_input_shared_memory();
+    if let Some(memory) = map_env_shmem(CMPLOG_SHM_ENV) {
+        unsafe {
+            CMPLOG_MAP_PTR = memory.as_mut_ptr().cast::<CmpLogMap>();
+            CMPLOG_ENABLED = 1;
+        }
+    }
+    if let Some(memory) = map_env_shmem(CRASH_SHM_ENV) {
+        install_crash_handler(ChildCrashInfo {
+            addr: memory.as_mut_ptr() as usize,
+            size: memory.len(),
+        });
+    }
+    start_forkserver();
+}
+
+/// Run as the target of the `ForkserverExecutor`, never returns
+pub fn run_target() -> ! {
+    let args: Vec<String> = env::args().collect();
+    if libfuzzer_initialize(&args) == -1 {
+        eprintln!("Warning: LLVMFuzzerInitialize failed with -1");
+    }
+
+    #[cfg(feature = "forkserver")]
+    start();
+
+    // A fresh child for every input from here on
+    #[cfg(feature = "forkserver")]
+    let shared = unsafe {
+        (!__afl_fuzz_ptr.is_null())
+            .then(|| slice::from_raw_parts(__afl_fuzz_ptr, *__afl_fuzz_len as usize).to_vec())
+    };
+    #[cfg(not(feature = "forkserver"))]
+    let shared = None;
+    let input = shared.unwrap_or_else(|| {
+        let mut input = Vec::new();
+        io::stdin()
+            .read_to_end(&mut input)
+            .expect("Failed to read the input");
+        input
+    });
+    libfuzzer_test_one_input(&input);
+    process::exit(0);
+}
@@ -0,0 +1,324 @@
+//! Hangs: timeouts are kept apart from the crashes, in their own on-disk corpus.
+//!
+//! [`HangFeedback`] only notes an input that ran past the timeout of the executor (`--timeout`)
+//! in the state, nothing is run again there. [`HangStage`], a stage of the fuzzing loop, takes
+//! the noted inputs and runs each once more in a fresh process of this binary (the target mode
+//! of [`crate::forkserver`]) with the longer `--hang-timeout`. Only an input that does not finish
+//! then either is stored in the hangs directory (`--hangs`), with the measured run time in its
+//! metadata; a slow input that finishes is dropped. A timeout is never a crash, the number of hangs is reported to the monitor as
+//! `hangs`.
+use core::time::Duration;
+use std::{
+    borrow::Cow,
+    env,
+    io::Write,
+    marker::PhantomData,
+    mem,
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::Instant,
+};
+
+use libafl::{
+    corpus::{Corpus, OnDiskCorpus, Testcase},
+    events::{Event, EventFirer},
+    executors::ExitKind,
+    feedbacks::Feedback,
+    inputs::{HasTargetBytes, Input, UsesInput},
+    monitors::{AggregatorOps, UserStats, UserStatsValue},
+    observers::{ObserversTuple, TimeObserver},
+    stages::Stage,
+    state::{HasCorpus, HasExecutions, HasStartTime, State, UsesState},
+    Error, HasMetadata,
+};
+use libafl_bolts::{
+    tuples::{Handle, Handled, MatchNameRef},
+    AsSlice, Named, SerdeAny,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{afl_output, forkserver};
+
+/// How often the re-run is polled for its end
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The run times of a hang
+#[derive(Debug, Clone, Serialize, Deserialize, SerdeAny)]
+pub struct HangMetadata {
+    /// Run time of the execution that timed out, in milliseconds
+    pub run_time_ms: u64,
+    /// Timeout of the fuzzing executions, in milliseconds
+    pub timeout_ms: u64,
+    /// The re-run did not finish within this many milliseconds
+    pub hang_timeout_ms: u64,
+}
+
+/// The number of hangs saved by this client, kept in the state so it survives restarts
+#[derive(Debug, Default, Clone, Serialize, Deserialize, SerdeAny)]
+pub struct HangsMetadata {
+    pub count: u64,
+}
+
+/// An input that timed out, not run again yet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HangCandidate {
+    pub input: Vec<u8>,
+    pub run_time_ms: u64,
+    /// Executions of the client when it timed out
+    pub executions: u64,
+}
+
+/// The timeouts waiting for the [`HangStage`], in the state so they survive the restart that
+/// follows a timeout of the in-process executor
+#[derive(Debug, Default, Clone, Serialize, Deserialize, SerdeAny)]
+pub struct HangCandidatesMetadata {
+    pub candidates: Vec<HangCandidate>,
+}
+
+/// Notes the timeouts for the [`HangStage`], never interesting by itself.
+///
+/// Combine it with `feedback_or!` in the objective, instead of a `TimeoutFeedback`.
+#[derive(Debug, Clone)]
+pub struct HangFeedback {
+    time_handle: Handle<TimeObserver>,
+    timeout: Duration,
+}
+
+impl HangFeedback {
+    pub fn new(time_observer: &TimeObserver, timeout: Duration) -> Self {
+        Self {
+            time_handle: time_observer.handle(),
+            timeout,
+        }
+    }
+}
+
+impl Named for HangFeedback {
+    fn name(&self) -> &Cow<'static, str> {
+        static NAME: Cow<'static, str> = Cow::Borrowed("hangs");
+        &NAME
+    }
+}
+
+impl<S> Feedback<S> for HangFeedback
+where
+    S: State + HasExecutions + HasMetadata,
+    S::Input: HasTargetBytes,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        input: &S::Input,
+        observers: &OT,
+        exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<State = S>,
+        OT: ObserversTuple<S>,
+    {
+        if *exit_kind != ExitKind::Timeout {
+            return Ok(false);
+        }
+        let run_time = observers
+            .get(&self.time_handle)
+            .and_then(|observer| *observer.last_runtime())
+            .unwrap_or(self.timeout);
+        let candidate = HangCandidate {
+            input: input.target_bytes().as_slice().to_vec(),
+            run_time_ms: run_time.as_millis() as u64,
+            executions: *state.executions(),
+        };
+        state
+            .metadata_or_insert_with(HangCandidatesMetadata::default)
+            .candidates
+            .push(candidate);
+        Ok(false)
+    }
+}
+
+/// Runs the timeouts noted by the [`HangFeedback`] again and stores the confirmed hangs
+#[derive(Debug)]
+pub struct HangStage<I, E, EM, Z> {
+    timeout: Duration,
+    hang_timeout: Duration,
+    hangs: OnDiskCorpus<I>,
+    afl_names: bool,
+    phantom: PhantomData<(E, EM, Z)>,
+}
+
+impl<I, E, EM, Z> HangStage<I, E, EM, Z>
+where
+    I: Input,
+{
+    /// With `afl_names` the hangs are named like the AFL++ `hangs/` entries
+    pub fn new(
+        timeout: Duration,
+        hang_timeout: Duration,
+        dir: &Path,
+        afl_names: bool,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            timeout,
+            hang_timeout,
+            hangs: OnDiskCorpus::new(dir)?,
+            afl_names,
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<I, E, EM, Z> UsesState for HangStage<I, E, EM, Z>
+where
+    Z: UsesState,
+{
+    type State = Z::State;
+}
+
+impl<I, E, EM, Z> Stage<E, EM, Z> for HangStage<I, E, EM, Z>
+where
+    I: Input + From<Vec<u8>>,
+    E: UsesState<State = Z::State>,
+    EM: EventFirer<State = Z::State>,
+    Z: UsesState,
+    Z::State: State + UsesInput<Input = I> + HasCorpus + HasExecutions + HasStartTime + HasMetadata,
+{
+    fn perform(
+        &mut self,
+        _fuzzer: &mut Z,
+        _executor: &mut E,
+        state: &mut Z::State,
+        manager: &mut EM,
+    ) -> Result<(), Error> {
+        let Some(noted) = state.metadata_map_mut().get_mut::<HangCandidatesMetadata>() else {
+            return Ok(());
+        };
+        let candidates = mem::take(&mut noted.candidates);
+        for candidate in candidates {
+            if let Some(elapsed) = rerun(&candidate.input, self.hang_timeout)? {
+                println!(
+                    "Timeout not confirmed, the input finished in {} ms when run again",
+                    elapsed.as_millis()
+                );
+                continue;
+            }
+
+            let mut testcase =
+                Testcase::with_executions(I::from(candidate.input), candidate.executions);
+            testcase.add_metadata(HangMetadata {
+                run_time_ms: candidate.run_time_ms,
+                timeout_ms: self.timeout.as_millis() as u64,
+                hang_timeout_ms: self.hang_timeout.as_millis() as u64,
+            });
+            let hangs = state.metadata_or_insert_with(HangsMetadata::default);
+            hangs.count += 1;
+            let count = hangs.count;
+            if self.afl_names {
+                let name = afl_output::afl_name(state, (count - 1) as usize);
+                *testcase.filename_mut() = Some(name);
+            }
+            self.hangs.add(testcase)?;
+
+            manager.fire(
+                state,
+                Event::UpdateUserStats {
+                    name: Cow::Borrowed("hangs"),
+                    value: UserStats::new(UserStatsValue::Number(count), AggregatorOps::Sum),
+                    phantom: PhantomData,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Run the input in a fresh target process, its run time if it ends within `timeout`
+fn rerun(input: &[u8], timeout: Duration) -> Result<Option<Duration>, Error> {
+    // Not the coverage map of a `--forkserver` client
+    let mut child = Command::new(env::current_exe()?)
+        .env(forkserver::TARGET_ENV, "1")
+        .env_remove("__AFL_SHM_ID")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    // The target reads the input up to the end of stdin, closed when dropped here.
+    // A target that dies before reading it all is no hang, ignore the broken pipe
+    let _ = child.stdin.take().unwrap().write_all(input);
+
+    let start = Instant::now();
+    loop {
+        if child.try_wait()?.is_some() {
+            return Ok(Some(start.elapsed()));
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use libafl::{
+        corpus::InMemoryCorpus,
+        events::SimpleEventManager,
+        executors::ExitKind,
+        feedbacks::{ConstFeedback, Feedback},
+        inputs::BytesInput,
+        monitors::NopMonitor,
+        observers::TimeObserver,
+        state::StdState,
+        HasMetadata,
+    };
+    use libafl_bolts::{rands::StdRand, tuples::tuple_list};
+
+    use super::{HangCandidatesMetadata, HangFeedback};
+
+    #[test]
+    fn timeouts_are_noted_for_the_stage() {
+        let time_observer = TimeObserver::new("time");
+        let mut feedback = HangFeedback::new(&time_observer, Duration::from_millis(100));
+        let observers = tuple_list!(time_observer);
+        let mut state = StdState::new(
+            StdRand::with_seed(0),
+            InMemoryCorpus::<BytesInput>::new(),
+            InMemoryCorpus::new(),
+            &mut ConstFeedback::new(false),
+            &mut ConstFeedback::new(false),
+        )
+        .unwrap();
+        let mut mgr = SimpleEventManager::new(NopMonitor::new());
+        let input = BytesInput::new(b"slow".to_vec());
+
+        for exit_kind in [ExitKind::Ok, ExitKind::Crash, ExitKind::Timeout] {
+            // Never a solution by itself, the stage decides after the re-run
+            let interesting = feedback
+                .is_interesting(&mut state, &mut mgr, &input, &observers, &exit_kind)
+                .unwrap();
+            assert!(!interesting);
+        }
+
+        // Only the timeout, with the timeout as run time when the observer measured none
+        let candidates = &state
+            .metadata::<HangCandidatesMetadata>()
+            .unwrap()
+            .candidates;
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].input, b"slow");
+        assert_eq!(candidates[0].run_time_ms, 100);
+    }
+}
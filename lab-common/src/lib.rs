@@ -0,0 +1,15 @@
+//! The parts the libpng, libexif (fuzzing-101 step-2) and appsec_guide fuzzers have in common:
+//! the campaign outputs, the extra stages and feedbacks, and the runtime of the target builds.
+pub mod afl_output;
+pub mod cmin;
+pub mod coverage;
+pub mod dedup;
+pub mod forkserver;
+pub mod hangs;
+pub mod monitor;
+pub mod replay;
+pub mod resume;
+pub mod sanitizers;
+pub mod seed;
+pub mod sync;
+pub mod tmin;
@@ -0,0 +1,87 @@
+//! Terminal UI (`--tui`): a table of the clients, the coverage graph, the time of the last
+//! finds and a log pane, instead of scrolling stat lines.
+//!
+//! When stdout is not a terminal (redirected to a file, CI), the stats are printed as lines
+//! like without `--tui`.
+use core::time::Duration;
+use std::io::{self, IsTerminal};
+
+use libafl::monitors::{tui::TuiMonitor, ClientStats, Monitor, MultiMonitor};
+use libafl_bolts::ClientId;
+
+/// The monitor picked at startup
+#[derive(Clone)]
+pub enum FuzzerMonitor {
+    Tui(TuiMonitor),
+    Lines(MultiMonitor<fn(&str)>),
+}
+
+impl FuzzerMonitor {
+    /// The terminal UI with `tui` if stdout is a terminal, else the stat lines
+    pub fn new(tui: bool, title: &str) -> Self {
+        if tui && io::stdout().is_terminal() {
+            return Self::Tui(
+                TuiMonitor::builder()
+                    .title(title.to_string())
+                    .enhanced_graphics(true)
+                    .build(),
+            );
+        }
+        if tui {
+            println!("stdout is not a terminal, printing the stats as lines");
+        }
+        Self::Lines(MultiMonitor::new(print_line as fn(&str)))
+    }
+
+    pub fn is_tui(&self) -> bool {
+        matches!(self, Self::Tui(_))
+    }
+}
+
+fn print_line(line: &str) {
+    println!("{line}");
+}
+
+impl Monitor for FuzzerMonitor {
+    fn client_stats_mut(&mut self) -> &mut Vec<ClientStats> {
+        match self {
+            Self::Tui(monitor) => monitor.client_stats_mut(),
+            Self::Lines(monitor) => monitor.client_stats_mut(),
+        }
+    }
+
+    fn client_stats(&self) -> &[ClientStats] {
+        match self {
+            Self::Tui(monitor) => monitor.client_stats(),
+            Self::Lines(monitor) => monitor.client_stats(),
+        }
+    }
+
+    fn start_time(&self) -> Duration {
+        match self {
+            Self::Tui(monitor) => monitor.start_time(),
+            Self::Lines(monitor) => monitor.start_time(),
+        }
+    }
+
+    fn set_start_time(&mut self, time: Duration) {
+        match self {
+            Self::Tui(monitor) => monitor.set_start_time(time),
+            Self::Lines(monitor) => monitor.set_start_time(time),
+        }
+    }
+
+    fn aggregate(&mut self, name: &str) {
+        match self {
+            Self::Tui(monitor) => monitor.aggregate(name),
+            Self::Lines(monitor) => monitor.aggregate(name),
+        }
+    }
+
+    fn display(&mut self, event_msg: &str, sender_id: ClientId) {
+        match self {
+            Self::Tui(monitor) => monitor.display(event_msg, sender_id),
+            Self::Lines(monitor) => monitor.display(event_msg, sender_id),
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! Replay mode (`--replay`): run inputs through the harness and executor without fuzzing.
+//!
+//! Each input runs in a forked child through the same in-process executor the fuzzer
+//! uses, so a crash or timeout is reported and the replay goes on with the next input.
+//! The child sends the exit kind and the covered edges of the run back through a pipe, a
+//! crash or timeout from the handler of the executor, so every outcome is reported the same way.
+//! For `--coverage` each child also writes its own profile, see [`crate::coverage`].
+use core::time::Duration;
+use std::{
+    borrow::Cow,
+    env,
+    ffi::{c_char, c_int, CString},
+    fs::{self, File},
+    io::{self, Read, Write},
+    mem,
+    os::{fd::FromRawFd, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
+};
+
+use libafl::{
+    corpus::InMemoryCorpus,
+    events::{EventFirer, SimpleEventManager},
+    executors::{inprocess::InProcessExecutor, ExitKind, HasObservers},
+    feedbacks::{ConstFeedback, Feedback},
+    fuzzer::{ExecutesInput, HasObjective, StdFuzzer},
+    inputs::{BytesInput, HasTargetBytes},
+    monitors::SimpleMonitor,
+    observers::{MapObserver, ObserversTuple},
+    schedulers::QueueScheduler,
+    state::{State, StdState},
+    Error,
+};
+use libafl_bolts::{
+    current_time,
+    rands::StdRand,
+    tuples::{tuple_list, Handle, Handled, MatchNameRef},
+    AsSlice, Named,
+};
+use libafl_targets::{libfuzzer_initialize, libfuzzer_test_one_input};
+
+use crate::cmin::{collect_files, EdgesObserver};
+
+/// Run every file (or every file in the directories) once, returns `false` on any crash or timeout.
+///
+/// The coverage is read through the observer made by `edges_observer`. With a `profile_dir`, the
+/// profile of each run is written to `<profile_dir>/<n>.profraw`.
+pub fn replay(
+    paths: &[PathBuf],
+    timeout: Duration,
+    profile_dir: Option<&Path>,
+    edges_observer: fn() -> EdgesObserver,
+) -> Result<bool, Error> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            collect_files(path, &mut files)?;
+        } else {
+            files.push((path.clone(), 0));
+        }
+    }
+
+    let edges_observer = edges_observer();
+    let edges_handle = edges_observer.handle();
+
+    let mut feedback = ConstFeedback::new(false);
+    let mut objective = RunReportFeedback {
+        edges_handle: edges_handle.clone(),
+        fd: -1,
+    };
+    let mut state = StdState::new(
+        StdRand::new(),
+        InMemoryCorpus::<BytesInput>::new(),
+        InMemoryCorpus::new(),
+        &mut feedback,
+        &mut objective,
+    )?;
+    let mut fuzzer = StdFuzzer::new(QueueScheduler::new(), feedback, objective);
+    let mut mgr = SimpleEventManager::new(SimpleMonitor::new(|s| println!("{s}")));
+
+    let mut harness = |input: &BytesInput| {
+        libfuzzer_test_one_input(input.target_bytes().as_slice());
+        ExitKind::Ok
+    };
+    let mut executor = InProcessExecutor::with_timeout(
+        &mut harness,
+        tuple_list!(edges_observer),
+        &mut fuzzer,
+        &mut state,
+        &mut mgr,
+        timeout,
+    )?;
+
+    let args: Vec<String> = env::args().collect();
+    if libfuzzer_initialize(&args) == -1 {
+        println!("Warning: LLVMFuzzerInitialize failed with -1");
+    }
+
+    if let Some(dir) = profile_dir {
+        fs::create_dir_all(dir)?;
+    }
+
+    let (mut crashes, mut timeouts) = (0, 0);
+    for (i, (path, _)) in files.iter().enumerate() {
+        let input = BytesInput::new(fs::read(path)?);
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let start = current_time();
+        match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error().into()),
+            0 => {
+                unsafe { libc::close(fds[0]) };
+                fuzzer.objective_mut().fd = fds[1];
+                // A crash or timeout ends here, reported by the objective in the handler of the executor
+                let exit_kind = fuzzer
+                    .execute_input(&mut state, &mut executor, &mut mgr, &input)
+                    .unwrap_or(ExitKind::Crash);
+                let edges = executor.observers()[&edges_handle].count_bytes();
+                send_report(fds[1], &exit_kind, edges);
+                if let Some(dir) = profile_dir {
+                    write_profile(&dir.join(format!("{i}.profraw")));
+                }
+                let _ = io::stdout().flush();
+                unsafe { libc::_exit(0) }
+            }
+            pid => {
+                unsafe { libc::close(fds[1]) };
+                let mut report = String::new();
+                let read = unsafe { File::from_raw_fd(fds[0]) }.read_to_string(&mut report);
+                let mut status = 0;
+                unsafe { libc::waitpid(pid, &mut status, 0) };
+                let elapsed = current_time() - start;
+
+                let outcome = read.ok().and(report.split_once(' '));
+                let exit_kind = match outcome {
+                    Some((exit_kind, edges)) => {
+                        println!(
+                            "{}: {exit_kind} in {} ms, {edges} edges",
+                            path.display(),
+                            elapsed.as_millis()
+                        );
+                        exit_kind
+                    }
+                    // Died before the executor could report, e.g. in its crash handler
+                    None => {
+                        let reason = if libc::WIFSIGNALED(status) {
+                            format!("signal {}", libc::WTERMSIG(status))
+                        } else {
+                            format!("exit code {}", libc::WEXITSTATUS(status))
+                        };
+                        println!(
+                            "{}: Crash in {} ms ({reason}), no edges reported",
+                            path.display(),
+                            elapsed.as_millis()
+                        );
+                        "Crash"
+                    }
+                };
+                match exit_kind {
+                    "Ok" => (),
+                    "Timeout" => timeouts += 1,
+                    _ => crashes += 1,
+                }
+            }
+        }
+    }
+
+    println!(
+        "replay: {} inputs, {crashes} crashes, {timeouts} timeouts",
+        files.len()
+    );
+    Ok(crashes == 0 && timeouts == 0)
+}
+
+/// `<exit kind> <edges>` of a run, for the parent
+fn send_report(fd: c_int, exit_kind: &ExitKind, edges: usize) {
+    let report = format!("{exit_kind:?} {edges}");
+    unsafe { libc::write(fd, report.as_ptr().cast(), report.len()) };
+}
+
+/// The objective of the replay: never a solution, it only sends the report of the run.
+/// Evaluated by the executor for a crash or timeout, just before the child exits
+#[derive(Debug)]
+struct RunReportFeedback {
+    edges_handle: Handle<EdgesObserver>,
+    /// The pipe to the parent, set in the child
+    fd: c_int,
+}
+
+impl Named for RunReportFeedback {
+    fn name(&self) -> &Cow<'static, str> {
+        static NAME: Cow<'static, str> = Cow::Borrowed("RunReportFeedback");
+        &NAME
+    }
+}
+
+impl<S> Feedback<S> for RunReportFeedback
+where
+    S: State,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _input: &S::Input,
+        observers: &OT,
+        exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<State = S>,
+        OT: ObserversTuple<S>,
+    {
+        let edges = observers
+            .get(&self.edges_handle)
+            .map_or(0, |observer| observer.count_bytes());
+        send_report(self.fd, exit_kind, edges);
+        Ok(false)
+    }
+}
+
+/// Write the profile counters of this process to `path`, if it is a `LIBAFL_COVERAGE=1` build.
+///
+/// The profile runtime only writes at `exit`, which the forked children never reach.
+/// The wrapper links that build with `-rdynamic`, so the runtime can be found here.
+fn write_profile(path: &Path) {
+    type SetFilename = unsafe extern "C" fn(*const c_char);
+    type WriteFile = unsafe extern "C" fn() -> c_int;
+
+    let set_filename =
+        unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"__llvm_profile_set_filename".as_ptr()) };
+    let write_file =
+        unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"__llvm_profile_write_file".as_ptr()) };
+    if set_filename.is_null() || write_file.is_null() {
+        return;
+    }
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    unsafe {
+        mem::transmute::<*mut libc::c_void, SetFilename>(set_filename)(path.as_ptr());
+        mem::transmute::<*mut libc::c_void, WriteFile>(write_file)();
+    }
+}
@@ -0,0 +1,233 @@
+//! Resume (`--resume`): a client goes on from its queue on disk and the state saved next to it.
+//!
+//! The queue entries alone would be run again like new seeds. So [`SaveStateStage`] writes the
+//! rest of the state every [`SAVE_INTERVAL`]: the RNG, the executions, the metadata (scheduler,
+//! coverage seen so far, stats) and the metadata of every entry, by its file name. The inputs
+//! are not in it, [`load_state`] reads them back from the queue. The solutions stay on disk
+//! and are not read back. Resuming needs the same options as the saved run.
+//!
+//! A queue with the entries of an earlier run is refused without `--resume`, `--force` removes
+//! it and starts over, see [`check_queue`].
+use core::time::Duration;
+use std::{
+    fs,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+use libafl::{
+    corpus::{Corpus, Testcase},
+    inputs::Input,
+    stages::Stage,
+    state::{HasCorpus, HasExecutions, HasRand, State, UsesState},
+    Error, HasMetadata, HasNamedMetadata,
+};
+use libafl_bolts::{
+    current_time,
+    serdeany::{NamedSerdeAnyMap, SerdeAnyMap},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// How often the state is saved
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The saved state of the client whose queue is `queue_dir`, hidden among the entries
+pub fn state_path(queue_dir: &Path) -> PathBuf {
+    queue_dir.join(".state")
+}
+
+/// Fails if `queue_dir` has entries of an earlier run and the client does not `resume`, they
+/// would be run again like new seeds; with `force` the queue is removed instead
+pub fn check_queue(queue_dir: &Path, resume: bool, force: bool) -> Result<(), Error> {
+    if resume {
+        return Ok(());
+    }
+    let has_entries = fs::read_dir(queue_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .any(|entry| !entry.file_name().to_string_lossy().starts_with('.'));
+    if !has_entries {
+        return Ok(());
+    }
+    if !force {
+        return Err(Error::illegal_argument(format!(
+            "{} has the queue of an earlier run, go on with --resume or start over with --force",
+            queue_dir.display()
+        )));
+    }
+    println!(
+        "Removing the queue of the earlier run in {}",
+        queue_dir.display()
+    );
+    fs::remove_dir_all(queue_dir)?;
+    Ok(())
+}
+
+/// What [`SaveStateStage`] writes, in the order of [`SavedState`]
+#[derive(Serialize)]
+struct SavedStateRef<'a, R> {
+    rand: &'a R,
+    executions: u64,
+    metadata: &'a SerdeAnyMap,
+    named_metadata: &'a NamedSerdeAnyMap,
+    queue: Vec<SavedEntryRef<'a>>,
+}
+
+#[derive(Serialize)]
+struct SavedEntryRef<'a> {
+    file_name: &'a str,
+    metadata: &'a SerdeAnyMap,
+    exec_time: Option<Duration>,
+    executions: u64,
+}
+
+/// What [`load_state`] reads back
+#[derive(Deserialize)]
+struct SavedState<R> {
+    rand: R,
+    executions: u64,
+    metadata: SerdeAnyMap,
+    named_metadata: NamedSerdeAnyMap,
+    /// In the order of the corpus, so the ids in the scheduler metadata stay the same
+    queue: Vec<SavedEntry>,
+}
+
+#[derive(Deserialize)]
+struct SavedEntry {
+    file_name: String,
+    metadata: SerdeAnyMap,
+    exec_time: Option<Duration>,
+    executions: u64,
+}
+
+/// Restores the state saved at `path` into the fresh `state`, with the entries of the queue
+/// next to it; `false` if there is none yet
+pub fn load_state<S>(path: &Path, state: &mut S) -> Result<bool, Error>
+where
+    S: HasRand + HasExecutions + HasMetadata + HasNamedMetadata + HasCorpus,
+    S::Rand: DeserializeOwned,
+{
+    if !path.exists() {
+        return Ok(false);
+    }
+    let bytes = fs::read(path)?;
+    let saved: SavedState<S::Rand> = postcard::from_bytes(&bytes).map_err(|err| {
+        Error::serialize(format!("Invalid saved state {}: {err}", path.display()))
+    })?;
+
+    let queue_dir = path.parent().unwrap_or(Path::new("."));
+    for entry in saved.queue {
+        let input = S::Input::from_file(queue_dir.join(&entry.file_name)).map_err(|err| {
+            Error::illegal_state(format!(
+                "Entry {} of the saved state is not in {} ({err}), start over with --force",
+                entry.file_name,
+                queue_dir.display()
+            ))
+        })?;
+        // The corpus writes the entry again, under the same name only without the old lock
+        let _ = fs::remove_file(queue_dir.join(format!(".{}.lafl_lock", entry.file_name)));
+        let mut testcase = Testcase::new(input);
+        *testcase.filename_mut() = Some(entry.file_name);
+        *testcase.metadata_map_mut() = entry.metadata;
+        if let Some(exec_time) = entry.exec_time {
+            testcase.set_exec_time(exec_time);
+        }
+        *testcase.executions_mut() = entry.executions;
+        state.corpus_mut().add(testcase)?;
+    }
+
+    *state.rand_mut() = saved.rand;
+    *state.executions_mut() = saved.executions;
+    *state.metadata_map_mut() = saved.metadata;
+    *state.named_metadata_map_mut() = saved.named_metadata;
+    Ok(true)
+}
+
+/// Saves the state, without the inputs, to a file every [`SAVE_INTERVAL`]
+#[derive(Debug)]
+pub struct SaveStateStage<E, EM, Z> {
+    path: PathBuf,
+    last_save: Duration,
+    phantom: PhantomData<(E, EM, Z)>,
+}
+
+impl<E, EM, Z> SaveStateStage<E, EM, Z> {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            last_save: Duration::ZERO,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<E, EM, Z> UsesState for SaveStateStage<E, EM, Z>
+where
+    Z: UsesState,
+{
+    type State = Z::State;
+}
+
+impl<E, EM, Z> Stage<E, EM, Z> for SaveStateStage<E, EM, Z>
+where
+    E: UsesState<State = Z::State>,
+    EM: UsesState<State = Z::State>,
+    Z: UsesState,
+    Z::State: State + HasRand + HasExecutions + HasMetadata + HasNamedMetadata + HasCorpus,
+    <Z::State as HasRand>::Rand: Serialize,
+{
+    fn perform(
+        &mut self,
+        _fuzzer: &mut Z,
+        _executor: &mut E,
+        state: &mut Z::State,
+        _manager: &mut EM,
+    ) -> Result<(), Error> {
+        let now = current_time();
+        if now.saturating_sub(self.last_save) < SAVE_INTERVAL {
+            return Ok(());
+        }
+        self.last_save = now;
+
+        let corpus = state.corpus();
+        let testcases = corpus
+            .ids()
+            .map(|id| corpus.get(id))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let testcases: Vec<_> = testcases.iter().map(|testcase| testcase.borrow()).collect();
+        let queue = testcases
+            .iter()
+            .filter_map(|testcase| {
+                Some(SavedEntryRef {
+                    file_name: testcase.filename().as_deref()?,
+                    metadata: testcase.metadata_map(),
+                    exec_time: *testcase.exec_time(),
+                    executions: *testcase.executions(),
+                })
+            })
+            .collect();
+        let saved = SavedStateRef {
+            rand: state.rand(),
+            executions: *state.executions(),
+            metadata: state.metadata_map(),
+            named_metadata: state.named_metadata_map(),
+            queue,
+        };
+        let bytes = postcard::to_allocvec(&saved)
+            .map_err(|err| Error::serialize(format!("Failed to save the state: {err}")))?;
+        // Written aside first, a fuzzer killed while saving keeps the previous state
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
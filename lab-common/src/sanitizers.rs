@@ -0,0 +1,294 @@
+//! Runtime options and reports of the sanitizer builds (`LIBAFL_SANITIZER` of the compiler wrappers).
+//!
+//! The sanitizer runtimes read the `__*san_default_options` before `main`, too early for the
+//! fuzzer to set `ASAN_OPTIONS` and friends itself. Every report has to end in `abort()` so the
+//! executor sees it as a crash. Options in the environment are applied after these and still win.
+//!
+//! [`SanitizerReportObserver`] catches the text of an ASan report through
+//! `__asan_set_error_report_callback`, [`SanitizerReportFeedback`] stores it, parsed into
+//! [`SanitizerReportMetadata`], in the `.metadata` file of the solution. A forkserver target
+//! catches its report itself and hands it over in a [`ChildCrashInfo`].
+use std::{
+    borrow::Cow,
+    ffi::{c_char, CStr},
+    mem,
+    sync::Mutex,
+};
+
+use libafl::{
+    corpus::Testcase,
+    events::EventFirer,
+    executors::ExitKind,
+    feedbacks::Feedback,
+    inputs::UsesInput,
+    observers::{Observer, ObserversTuple},
+    state::State,
+    Error, HasMetadata,
+};
+use libafl_bolts::{
+    tuples::{Handle, Handled, MatchNameRef},
+    Named, SerdeAny,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{dedup::is_runtime_frame, forkserver::ChildCrashInfo};
+
+#[no_mangle]
+pub extern "C" fn __asan_default_options() -> *const c_char {
+    c"abort_on_error=1:detect_leaks=0:allocator_may_return_null=1:symbolize=1".as_ptr()
+}
+
+#[no_mangle]
+pub extern "C" fn __ubsan_default_options() -> *const c_char {
+    c"halt_on_error=1:abort_on_error=1:print_stacktrace=1:symbolize=1".as_ptr()
+}
+
+#[no_mangle]
+pub extern "C" fn __msan_default_options() -> *const c_char {
+    c"halt_on_error=1:abort_on_error=1:symbolize=1".as_ptr()
+}
+
+/// The last report of the ASan runtime, written just before it aborts
+static LAST_REPORT: Mutex<Option<String>> = Mutex::new(None);
+
+extern "C" fn on_report(report: *const c_char) {
+    if report.is_null() {
+        return;
+    }
+    let report = unsafe { CStr::from_ptr(report) }
+        .to_string_lossy()
+        .into_owned();
+    *LAST_REPORT.lock().unwrap_or_else(|err| err.into_inner()) = Some(report);
+}
+
+/// Hands [`on_report`] to the ASan runtime, returns `false` if this is not an ASan build
+pub fn register_report_callback() -> bool {
+    type SetCallback = unsafe extern "C" fn(extern "C" fn(*const c_char));
+
+    let set_callback = unsafe {
+        libc::dlsym(
+            libc::RTLD_DEFAULT,
+            c"__asan_set_error_report_callback".as_ptr(),
+        )
+    };
+    if set_callback.is_null() {
+        return false;
+    }
+    unsafe { mem::transmute::<*mut libc::c_void, SetCallback>(set_callback)(on_report) };
+    true
+}
+
+/// Has the sanitizer runtime call `callback` before it aborts, returns `false` without one
+pub fn register_death_callback(callback: extern "C" fn()) -> bool {
+    type SetCallback = unsafe extern "C" fn(extern "C" fn());
+
+    let set_callback = unsafe {
+        libc::dlsym(
+            libc::RTLD_DEFAULT,
+            c"__sanitizer_set_death_callback".as_ptr(),
+        )
+    };
+    if set_callback.is_null() {
+        return false;
+    }
+    unsafe { mem::transmute::<*mut libc::c_void, SetCallback>(set_callback)(callback) };
+    true
+}
+
+/// The report caught by [`on_report`] since the last call
+pub fn take_last_report() -> Option<String> {
+    LAST_REPORT
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .take()
+}
+
+/// The sanitizer report of a crashing run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SanitizerReportObserver {
+    name: Cow<'static, str>,
+    report: Option<String>,
+    #[serde(skip)]
+    child: Option<ChildCrashInfo>,
+}
+
+impl SanitizerReportObserver {
+    pub fn new(name: &'static str) -> Self {
+        if !register_report_callback() {
+            println!("Not an ASan build, the crashes are stored without sanitizer report");
+        }
+        Self {
+            name: Cow::Borrowed(name),
+            report: None,
+            child: None,
+        }
+    }
+
+    /// Reads the reports of a forkserver target from `child`, it catches them itself
+    pub fn with_child(mut self, child: ChildCrashInfo) -> Self {
+        self.child = Some(child);
+        self
+    }
+
+    /// The full report of the last run, if it crashed with one
+    pub fn report(&self) -> Option<&str> {
+        self.report.as_deref()
+    }
+}
+
+impl Named for SanitizerReportObserver {
+    fn name(&self) -> &Cow<'static, str> {
+        &self.name
+    }
+}
+
+impl<S> Observer<S> for SanitizerReportObserver
+where
+    S: UsesInput,
+{
+    fn pre_exec(&mut self, _state: &mut S, _input: &S::Input) -> Result<(), Error> {
+        self.report = None;
+        take_last_report();
+        if let Some(child) = &self.child {
+            child.clear();
+        }
+        Ok(())
+    }
+
+    // In-process: called from the crash handler of the executor, after the runtime aborted
+    fn post_exec(
+        &mut self,
+        _state: &mut S,
+        _input: &S::Input,
+        exit_kind: &ExitKind,
+    ) -> Result<(), Error> {
+        if *exit_kind == ExitKind::Crash {
+            self.report = match &self.child {
+                Some(child) => child.report(),
+                None => take_last_report(),
+            };
+        }
+        Ok(())
+    }
+}
+
+/// What a sanitizer report says about a solution
+#[derive(Debug, Clone, Serialize, Deserialize, SerdeAny)]
+pub struct SanitizerReportMetadata {
+    /// e.g. `heap-buffer-overflow`, `heap-use-after-free`, `SEGV`
+    pub bug_type: String,
+    /// `READ` or `WRITE`
+    pub access: Option<String>,
+    pub access_size: Option<usize>,
+    /// The first frame of the target, e.g. `exif_mnote_data_canon_load exif-mnote-data-canon.c:240:3`
+    pub frame: Option<String>,
+    pub report: String,
+}
+
+impl SanitizerReportMetadata {
+    pub fn parse(report: &str) -> Self {
+        let mut bug_type = None;
+        let mut access = None;
+        let mut access_size = None;
+        let mut frame = None;
+        for line in report.lines().map(str::trim) {
+            if let Some((_, summary)) = line.split_once("SUMMARY: AddressSanitizer: ") {
+                // "SUMMARY: AddressSanitizer: heap-buffer-overflow exif-utils.c:92:8 in exif_get_sshort"
+                bug_type = summary.split_whitespace().next().map(str::to_string);
+            } else if let Some((_, error)) = line.split_once("ERROR: AddressSanitizer: ") {
+                // "ERROR: AddressSanitizer: SEGV on unknown address ...", the summary wins
+                if bug_type.is_none() {
+                    bug_type = error.split_whitespace().next().map(str::to_string);
+                }
+            } else if let Some((kind, size)) = line.split_once(" of size ") {
+                // "READ of size 2 at 0x602000000031 thread T0"
+                if access.is_none() && (kind == "READ" || kind == "WRITE") {
+                    access = Some(kind.to_string());
+                    access_size = size.split_whitespace().next().and_then(|s| s.parse().ok());
+                }
+            } else if let Some((_, cause)) = line.split_once("The signal is caused by a ") {
+                // "==1234==The signal is caused by a WRITE memory access."
+                if access.is_none() {
+                    access = cause.split_whitespace().next().map(str::to_string);
+                }
+            } else if line.starts_with('#') && frame.is_none() {
+                // "#0 0x4f6d2a in exif_mnote_data_olympus_load exif-mnote-data-olympus.c:80:3"
+                if let Some((_, location)) = line.split_once(" in ") {
+                    let function = location.split_whitespace().next().unwrap_or_default();
+                    if !is_runtime_frame(function) {
+                        frame = Some(location.to_string());
+                    }
+                }
+            }
+        }
+
+        Self {
+            bug_type: bug_type.unwrap_or_else(|| "unknown".into()),
+            access,
+            access_size,
+            frame,
+            report: report.to_string(),
+        }
+    }
+}
+
+/// Stores the sanitizer report of a solution, lets every input through
+#[derive(Debug, Clone)]
+pub struct SanitizerReportFeedback {
+    observer_handle: Handle<SanitizerReportObserver>,
+}
+
+impl SanitizerReportFeedback {
+    pub fn new(observer: &SanitizerReportObserver) -> Self {
+        Self {
+            observer_handle: observer.handle(),
+        }
+    }
+}
+
+impl Named for SanitizerReportFeedback {
+    fn name(&self) -> &Cow<'static, str> {
+        self.observer_handle.name()
+    }
+}
+
+impl<S> Feedback<S> for SanitizerReportFeedback
+where
+    S: State + HasMetadata,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _input: &S::Input,
+        _observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<State = S>,
+        OT: ObserversTuple<S>,
+    {
+        Ok(true)
+    }
+
+    fn append_metadata<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        observers: &OT,
+        testcase: &mut Testcase<S::Input>,
+    ) -> Result<(), Error>
+    where
+        OT: ObserversTuple<S>,
+        EM: EventFirer<State = S>,
+    {
+        // Without the observer the report stays unread
+        if let Some(report) = observers
+            .get(&self.observer_handle)
+            .and_then(|observer| observer.report())
+        {
+            testcase.add_metadata(SanitizerReportMetadata::parse(report));
+        }
+        Ok(())
+    }
+}
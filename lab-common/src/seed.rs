@@ -0,0 +1,230 @@
+//! RNG seed of a campaign (`--seed`), printed at startup and kept in the state of every client.
+//!
+//! Without `--seed` one is taken from the clock, the printed value reproduces the campaign.
+//! Each client derives its own seed from it and its core id, so the clients do not all
+//! mutate the same way, and a single-core run with the same seed, corpus and options
+//! draws the same random numbers.
+//!
+//! The same random numbers alone do not make the same campaign: the power schedules rate the
+//! testcases by their measured run time, and some stages go by the clock. With
+//! `--deterministic` the fuzzers schedule the corpus in order ([`DeterministicScheduler`]),
+//! skip the calibration and the power schedule, and turn off the stages that depend on time.
+use libafl::{
+    corpus::CorpusId,
+    inputs::UsesInput,
+    observers::ObserversTuple,
+    schedulers::{QueueScheduler, Scheduler},
+    state::{HasCorpus, UsesState},
+    Error,
+};
+use libafl_bolts::{core_affinity::CoreId, current_nanos, SerdeAny};
+use serde::{Deserialize, Serialize};
+
+/// The seeds of a client, kept in its state so they survive restarts
+#[derive(Debug, Clone, Serialize, Deserialize, SerdeAny)]
+pub struct SeedMetadata {
+    /// Seed of the campaign, `--seed`
+    pub seed: u64,
+    /// Seed of the RNG of this client
+    pub client_seed: u64,
+}
+
+/// The seed given with `--seed`, or a fresh one
+pub fn campaign_seed(seed: Option<u64>) -> u64 {
+    seed.unwrap_or_else(current_nanos)
+}
+
+/// The seed of the client on `core_id`, the splitmix64 mix of the campaign seed and the core
+pub fn client_seed(seed: u64, core_id: CoreId) -> u64 {
+    let mut z = seed.wrapping_add((core_id.0 as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// `CS`, or with `--deterministic` the corpus in order like a `QueueScheduler`
+#[derive(Debug, Clone)]
+pub struct DeterministicScheduler<CS, S> {
+    inner: CS,
+    queue: QueueScheduler<S>,
+    deterministic: bool,
+}
+
+impl<CS, S> DeterministicScheduler<CS, S> {
+    pub fn new(inner: CS, deterministic: bool) -> Self {
+        Self {
+            inner,
+            queue: QueueScheduler::new(),
+            deterministic,
+        }
+    }
+}
+
+impl<CS, S> UsesState for DeterministicScheduler<CS, S>
+where
+    CS: UsesState<State = S>,
+{
+    type State = S;
+}
+
+impl<CS, S> Scheduler for DeterministicScheduler<CS, S>
+where
+    CS: Scheduler<State = S>,
+    QueueScheduler<S>: Scheduler<State = S>,
+    S: HasCorpus,
+{
+    fn on_add(&mut self, state: &mut S, id: CorpusId) -> Result<(), Error> {
+        if self.deterministic {
+            self.queue.on_add(state, id)
+        } else {
+            self.inner.on_add(state, id)
+        }
+    }
+
+    fn on_evaluation<OT>(
+        &mut self,
+        state: &mut S,
+        input: &<S as UsesInput>::Input,
+        observers: &OT,
+    ) -> Result<(), Error>
+    where
+        OT: ObserversTuple<S>,
+    {
+        if self.deterministic {
+            self.queue.on_evaluation(state, input, observers)
+        } else {
+            self.inner.on_evaluation(state, input, observers)
+        }
+    }
+
+    fn next(&mut self, state: &mut S) -> Result<CorpusId, Error> {
+        if self.deterministic {
+            self.queue.next(state)
+        } else {
+            self.inner.next(state)
+        }
+    }
+
+    fn set_current_scheduled(
+        &mut self,
+        state: &mut S,
+        next_id: Option<CorpusId>,
+    ) -> Result<(), Error> {
+        if self.deterministic {
+            self.queue.set_current_scheduled(state, next_id)
+        } else {
+            self.inner.set_current_scheduled(state, next_id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use libafl::{
+        corpus::{Corpus, InMemoryCorpus},
+        events::SimpleEventManager,
+        executors::{inprocess::InProcessExecutor, ExitKind},
+        feedbacks::{ConstFeedback, MaxMapFeedback},
+        fuzzer::{Evaluator, Fuzzer, StdFuzzer},
+        inputs::{BytesInput, HasTargetBytes},
+        monitors::NopMonitor,
+        mutators::{havoc_mutations, StdScheduledMutator},
+        observers::StdMapObserver,
+        schedulers::QueueScheduler,
+        stages::StdMutationalStage,
+        state::{HasCorpus, StdState},
+    };
+    use libafl_bolts::{core_affinity::CoreId, rands::StdRand, tuples::tuple_list, AsSlice};
+
+    use super::{client_seed, DeterministicScheduler};
+
+    const MAP_SIZE: usize = 64;
+
+    static mut MAP: [u8; MAP_SIZE] = [0; MAP_SIZE];
+
+    /// An edge per value of the first byte, and one per matched byte of a magic prefix
+    fn harness(input: &BytesInput) -> ExitKind {
+        let target = input.target_bytes();
+        let bytes = target.as_slice();
+        let map = unsafe { &mut *ptr::addr_of_mut!(MAP) };
+        if let Some(&first) = bytes.first() {
+            map[usize::from(first) % (MAP_SIZE / 2)] = 1;
+        }
+        for (i, (byte, magic)) in bytes.iter().zip(b"LIBAFL-LAB").enumerate() {
+            if byte != magic {
+                break;
+            }
+            map[MAP_SIZE / 2 + i] = 1;
+        }
+        ExitKind::Ok
+    }
+
+    /// The corpus after `iterations` rounds of a deterministic single-core campaign
+    fn campaign(seed: u64, iterations: u64) -> Vec<Vec<u8>> {
+        let observer = unsafe {
+            StdMapObserver::from_mut_ptr("edges", ptr::addr_of_mut!(MAP).cast(), MAP_SIZE)
+        };
+        let mut feedback = MaxMapFeedback::new(&observer);
+        let mut objective = ConstFeedback::new(false);
+        let mut state = StdState::new(
+            StdRand::with_seed(client_seed(seed, CoreId(0))),
+            InMemoryCorpus::<BytesInput>::new(),
+            InMemoryCorpus::new(),
+            &mut feedback,
+            &mut objective,
+        )
+        .unwrap();
+        let scheduler = DeterministicScheduler::new(QueueScheduler::new(), true);
+        let mut fuzzer = StdFuzzer::new(scheduler, feedback, objective);
+        let mut mgr = SimpleEventManager::new(NopMonitor::new());
+        let mut harness = harness;
+        let mut executor = InProcessExecutor::new(
+            &mut harness,
+            tuple_list!(observer),
+            &mut fuzzer,
+            &mut state,
+            &mut mgr,
+        )
+        .unwrap();
+        fuzzer
+            .add_input(
+                &mut state,
+                &mut executor,
+                &mut mgr,
+                BytesInput::new(b"LIB".to_vec()),
+            )
+            .unwrap();
+
+        let mut stages = tuple_list!(StdMutationalStage::new(StdScheduledMutator::new(
+            havoc_mutations()
+        )));
+        fuzzer
+            .fuzz_loop_for(&mut stages, &mut executor, &mut state, &mut mgr, iterations)
+            .unwrap();
+
+        let corpus = state.corpus();
+        corpus
+            .ids()
+            .map(|id| {
+                let testcase = corpus.get(id).unwrap().borrow();
+                testcase
+                    .input()
+                    .as_ref()
+                    .unwrap()
+                    .target_bytes()
+                    .as_slice()
+                    .to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_same_corpus() {
+        let first = campaign(42, 200);
+        let second = campaign(42, 200);
+        assert!(first.len() > 1, "the campaign found nothing new");
+        assert_eq!(first, second);
+    }
+}
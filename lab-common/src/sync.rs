@@ -0,0 +1,441 @@
+//! Sync with other fuzzers (`--sync-dir`): their new inputs are run and the interesting ones kept.
+//!
+//! Every [`SYNC_INTERVAL`] [`SyncStage`] scans the foreign directories: an AFL++ output directory
+//! (the `queue/` of each of its instances, or of a single instance) or any plain directory of
+//! inputs, like a honggfuzz corpus. Every file is run once: the state keeps a high-water mark
+//! per queue, the id of the last AFL++ entry run or the mtime of the last plain file. The ones
+//! that make it into the corpus or the solutions carry their origin in [`SyncOriginMetadata`].
+//! The [`SyncOriginFeedback`] in the feedback and the objective adds it while the testcase is
+//! built, so it is in the `.metadata` file on disk as well.
+use core::time::Duration;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use libafl::{
+    corpus::Testcase,
+    events::EventFirer,
+    executors::{Executor, ExitKind},
+    feedbacks::Feedback,
+    fuzzer::Evaluator,
+    inputs::{Input, UsesInput},
+    observers::ObserversTuple,
+    stages::Stage,
+    state::{State, UsesState},
+    Error, HasMetadata,
+};
+use libafl_bolts::{current_time, Named, SerdeAny};
+use serde::{Deserialize, Serialize};
+
+/// How often the foreign directories are scanned
+const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Where a testcase imported from another fuzzer comes from
+#[derive(Debug, Clone, Serialize, Deserialize, SerdeAny)]
+pub struct SyncOriginMetadata {
+    /// The AFL++ instance, or the name of the plain directory
+    pub fuzzer: String,
+    pub path: PathBuf,
+}
+
+/// The origin of the input the [`SyncStage`] runs right now, with the name
+/// ([`Input::generate_name`]) of that input: after a restart in the middle of it, the other
+/// testcases do not take it over
+#[derive(Debug, Clone, Serialize, Deserialize, SerdeAny)]
+pub struct SyncCurrentMetadata {
+    pub input: String,
+    pub origin: SyncOriginMetadata,
+}
+
+/// Adds the [`SyncOriginMetadata`] of an imported input to its testcase, never interesting
+/// on its own
+#[derive(Debug, Default, Clone)]
+pub struct SyncOriginFeedback;
+
+impl SyncOriginFeedback {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Named for SyncOriginFeedback {
+    fn name(&self) -> &Cow<'static, str> {
+        static NAME: Cow<'static, str> = Cow::Borrowed("SyncOriginFeedback");
+        &NAME
+    }
+}
+
+impl<S> Feedback<S> for SyncOriginFeedback
+where
+    S: State + HasMetadata,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _input: &S::Input,
+        _observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<State = S>,
+        OT: ObserversTuple<S>,
+    {
+        Ok(false)
+    }
+
+    fn append_metadata<EM, OT>(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        _observers: &OT,
+        testcase: &mut Testcase<S::Input>,
+    ) -> Result<(), Error>
+    where
+        OT: ObserversTuple<S>,
+        EM: EventFirer<State = S>,
+    {
+        let Some(current) = state.metadata_map().get::<SyncCurrentMetadata>() else {
+            return Ok(());
+        };
+        let synced = testcase
+            .input()
+            .as_ref()
+            .is_some_and(|input| input.generate_name(0) == current.input);
+        if synced {
+            let origin = current.origin.clone();
+            testcase.add_metadata(origin);
+        }
+        Ok(())
+    }
+}
+
+/// Where a file is in its queue: AFL++ entries by their id, the other files by their mtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Position {
+    Id(u64),
+    Mtime(Duration),
+}
+
+impl Position {
+    /// `id:000123,src:...` is the AFL++ entry 123
+    fn of(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy();
+        let id = name
+            .strip_prefix("id:")
+            .and_then(|rest| rest.split(',').next())
+            .and_then(|id| id.parse().ok());
+        if let Some(id) = id {
+            return Some(Self::Id(id));
+        }
+        let mtime = fs::metadata(path).ok()?.modified().ok()?;
+        Some(Self::Mtime(mtime.duration_since(UNIX_EPOCH).ok()?))
+    }
+}
+
+/// How far the files of a foreign queue were run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncMark {
+    /// The highest AFL++ id run
+    pub id: Option<u64>,
+    /// The latest mtime of the other files run
+    pub mtime: Option<Duration>,
+}
+
+impl SyncMark {
+    fn is_new(&self, position: Position) -> bool {
+        match position {
+            Position::Id(id) => !self.id.is_some_and(|mark| id <= mark),
+            Position::Mtime(mtime) => !self.mtime.is_some_and(|mark| mtime <= mark),
+        }
+    }
+
+    fn advance(&mut self, position: Position) {
+        match position {
+            Position::Id(id) => self.id = self.id.max(Some(id)),
+            Position::Mtime(mtime) => self.mtime = self.mtime.max(Some(mtime)),
+        }
+    }
+}
+
+/// The high-water marks of the foreign queues, kept in the state so they survive restarts
+#[derive(Debug, Default, Clone, Serialize, Deserialize, SerdeAny)]
+pub struct SyncMetadata {
+    pub marks: HashMap<PathBuf, SyncMark>,
+}
+
+/// Imports the new files of the foreign directories every [`SYNC_INTERVAL`]
+#[derive(Debug)]
+pub struct SyncStage<E, EM, Z> {
+    dirs: Vec<PathBuf>,
+    last_sync: Duration,
+    phantom: PhantomData<(E, EM, Z)>,
+}
+
+impl<E, EM, Z> SyncStage<E, EM, Z> {
+    /// A stage that does nothing without directories
+    pub fn new(dirs: Vec<PathBuf>) -> Self {
+        Self {
+            dirs,
+            last_sync: Duration::ZERO,
+            phantom: PhantomData,
+        }
+    }
+}
+
+/// The queues in `dir`, with the name of the fuzzer they belong to
+fn queues(dir: &Path) -> Vec<(String, PathBuf)> {
+    let name = |path: &Path| {
+        path.file_name().map_or_else(
+            || path.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        )
+    };
+    if dir.join("queue").is_dir() {
+        return vec![(name(dir), dir.join("queue"))];
+    }
+    let instances: Vec<_> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.join("queue").is_dir())
+        .map(|path| (name(&path), path.join("queue")))
+        .collect();
+    if instances.is_empty() {
+        vec![(name(dir), dir.to_path_buf())]
+    } else {
+        instances
+    }
+}
+
+/// The files of `queue` past its `mark`
+fn new_in_queue(queue: &Path, mark: SyncMark) -> Vec<(Position, PathBuf)> {
+    let Ok(entries) = fs::read_dir(queue) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        // `.state/` of AFL++, the `.metadata` and lock files of LibAFL
+        .filter(|path| {
+            path.is_file()
+                && !path
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        })
+        .filter_map(|path| Some((Position::of(&path)?, path)))
+        .filter(|(position, _)| mark.is_new(*position))
+        .collect()
+}
+
+impl<E, EM, Z> UsesState for SyncStage<E, EM, Z>
+where
+    Z: UsesState,
+{
+    type State = Z::State;
+}
+
+impl<E, EM, Z> Stage<E, EM, Z> for SyncStage<E, EM, Z>
+where
+    E: Executor<EM, Z> + UsesState<State = Z::State>,
+    EM: UsesState<State = Z::State>,
+    Z: Evaluator<E, EM>,
+    Z::State: HasMetadata,
+{
+    fn perform(
+        &mut self,
+        fuzzer: &mut Z,
+        executor: &mut E,
+        state: &mut Z::State,
+        manager: &mut EM,
+    ) -> Result<(), Error> {
+        if self.dirs.is_empty() {
+            return Ok(());
+        }
+        let now = current_time();
+        if now.saturating_sub(self.last_sync) < SYNC_INTERVAL {
+            return Ok(());
+        }
+        self.last_sync = now;
+
+        let marks = &state.metadata_or_insert_with(SyncMetadata::default).marks;
+        let mut new_files = Vec::new();
+        for dir in &self.dirs {
+            // A directory that does not exist yet is scanned again next time
+            for (origin, queue) in queues(dir) {
+                let mark = marks.get(&queue).copied().unwrap_or_default();
+                new_files.extend(
+                    new_in_queue(&queue, mark)
+                        .into_iter()
+                        .map(|(position, path)| (queue.clone(), position, path, origin.clone())),
+                );
+            }
+        }
+        // Each queue in the order of the ids and mtimes
+        new_files.sort();
+
+        let (mut imported, mut added) = (0, 0);
+        for (queue, position, path, origin) in new_files {
+            // Marked first, an input that crashes is not run again after the restart
+            state
+                .metadata_or_insert_with(SyncMetadata::default)
+                .marks
+                .entry(queue)
+                .or_default()
+                .advance(position);
+            let Ok(input) = <Z::State as UsesInput>::Input::from_file(&path) else {
+                continue;
+            };
+            imported += 1;
+            state.add_metadata(SyncCurrentMetadata {
+                input: input.generate_name(0),
+                origin: SyncOriginMetadata {
+                    fuzzer: origin,
+                    path,
+                },
+            });
+            let result = fuzzer.evaluate_input(state, executor, manager, input);
+            state.metadata_map_mut().remove::<SyncCurrentMetadata>();
+            if let (_, Some(_)) = result? {
+                added += 1;
+            }
+        }
+        if imported > 0 {
+            println!("Synced {imported} new inputs, {added} added to the corpus");
+        }
+        Ok(())
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+    use std::{
+        env,
+        fs::{self, File},
+        path::{Path, PathBuf},
+        process,
+        time::UNIX_EPOCH,
+    };
+
+    use libafl::{
+        corpus::{InMemoryCorpus, Testcase},
+        events::SimpleEventManager,
+        feedbacks::{ConstFeedback, Feedback},
+        inputs::{BytesInput, Input},
+        monitors::NopMonitor,
+        state::StdState,
+        HasMetadata,
+    };
+    use libafl_bolts::{rands::StdRand, tuples::tuple_list};
+
+    use super::{
+        new_in_queue, queues, SyncCurrentMetadata, SyncMark, SyncOriginFeedback, SyncOriginMetadata,
+    };
+
+    /// The file names of the new files, in order
+    fn new_names(queue: &Path, mark: SyncMark) -> Vec<String> {
+        let mut files = new_in_queue(queue, mark);
+        files.sort();
+        files
+            .into_iter()
+            .map(|(_, path)| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn only_the_files_past_the_mark() {
+        let dir = env::temp_dir().join(format!("sync-test-{}", process::id()));
+        let queue = dir.join("afl-out/main/queue");
+        fs::create_dir_all(queue.join(".state")).unwrap();
+        for name in [
+            "id:000000,time:0,execs:0,orig:seed",
+            "id:000001,src:000000,op:havoc",
+        ] {
+            fs::write(queue.join(name), b"afl").unwrap();
+        }
+        let plain = dir.join("hfuzz");
+        fs::create_dir_all(&plain).unwrap();
+        for (name, secs) in [("a", 100), ("b", 200), (".lock", 300)] {
+            File::create(plain.join(name))
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        }
+
+        // The instances of an AFL++ output directory, else the directory itself
+        assert_eq!(
+            queues(&dir.join("afl-out")),
+            [("main".to_string(), queue.clone())]
+        );
+        assert_eq!(queues(&plain), [("hfuzz".to_string(), plain.clone())]);
+
+        let mut mark = SyncMark::default();
+        assert_eq!(new_names(&queue, mark).len(), 2);
+        for (position, _) in new_in_queue(&queue, mark) {
+            mark.advance(position);
+        }
+        assert_eq!(mark.id, Some(1));
+        assert!(new_names(&queue, mark).is_empty());
+        mark.id = Some(0);
+        assert_eq!(new_names(&queue, mark), ["id:000001,src:000000,op:havoc"]);
+
+        let mut mark = SyncMark::default();
+        assert_eq!(new_names(&plain, mark), ["a", "b"]);
+        mark.mtime = Some(Duration::from_secs(100));
+        assert_eq!(new_names(&plain, mark), ["b"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn origin_only_on_the_synced_input() {
+        let mut state = StdState::new(
+            StdRand::with_seed(0),
+            InMemoryCorpus::<BytesInput>::new(),
+            InMemoryCorpus::new(),
+            &mut ConstFeedback::new(false),
+            &mut ConstFeedback::new(false),
+        )
+        .unwrap();
+        let mut mgr = SimpleEventManager::new(NopMonitor::new());
+        let observers = tuple_list!();
+        let synced = BytesInput::new(b"synced".to_vec());
+        state.add_metadata(SyncCurrentMetadata {
+            input: synced.generate_name(0),
+            origin: SyncOriginMetadata {
+                fuzzer: "main".to_string(),
+                path: PathBuf::from("queue/id:000000"),
+            },
+        });
+
+        let mut feedback = SyncOriginFeedback::new();
+        let mut testcase = Testcase::new(synced);
+        feedback
+            .append_metadata(&mut state, &mut mgr, &observers, &mut testcase)
+            .unwrap();
+        let origin = testcase.metadata::<SyncOriginMetadata>().unwrap();
+        assert_eq!(origin.fuzzer, "main");
+
+        // A testcase found by the fuzzer while the synced input is still in the state
+        let mut testcase = Testcase::new(BytesInput::new(b"found".to_vec()));
+        feedback
+            .append_metadata(&mut state, &mut mgr, &observers, &mut testcase)
+            .unwrap();
+        assert!(!testcase.has_metadata::<SyncOriginMetadata>());
+    }
+}
@@ -0,0 +1,266 @@
+//! Testcase minimization (`--tmin`), like `afl-tmin`.
+//!
+//! Every run happens in a forked child, so a crash does not take the fuzzer down.
+//! A smaller input is only kept if it crashes the same way as the original one: same signal
+//! or exit code and the same stack hash [`crate::dedup`] gives it (the top `--crash-depth`
+//! frames), so the minimized crash stays in the bucket of the original one. The child hashes
+//! its stack in a signal handler, like the crash handler of the fuzzer, and sends the hash
+//! through a pipe.
+use core::time::Duration;
+use std::{
+    env,
+    ffi::c_int,
+    fs::{self, File},
+    io::{self, Read},
+    mem,
+    os::fd::FromRawFd,
+    path::{Path, PathBuf},
+    ptr,
+    sync::atomic::{AtomicI32, AtomicUsize, Ordering},
+    thread,
+    time::Instant,
+};
+
+use libafl::Error;
+use libafl_targets::{libfuzzer_initialize, libfuzzer_test_one_input};
+
+use crate::{cmin::collect_files, dedup};
+
+/// How a forked run of the harness ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Signal(i32),
+    Exit(i32),
+    Timeout,
+}
+
+/// What has to stay the same while the input shrinks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CrashId {
+    outcome: Outcome,
+    stack_hash: Option<u64>,
+}
+
+/// Minimize the given crashes, or all crashes in the given directories, keeping the hash of
+/// their top `depth` frames
+pub fn minimize_crashes(paths: &[PathBuf], timeout: Duration, depth: usize) -> Result<(), Error> {
+    let args: Vec<String> = env::args().collect();
+    if libfuzzer_initialize(&args) == -1 {
+        println!("Warning: LLVMFuzzerInitialize failed with -1");
+    }
+
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            collect_files(path, &mut files)?;
+        } else {
+            files.push((path.clone(), 0));
+        }
+    }
+
+    for (file, _) in &files {
+        if file.extension().is_some_and(|ext| ext == "min") {
+            continue;
+        }
+        minimize_file(file, timeout, depth)?;
+    }
+    Ok(())
+}
+
+/// Shrink one crash and write it to `<file>.min`
+fn minimize_file(path: &Path, timeout: Duration, depth: usize) -> Result<(), Error> {
+    let original = fs::read(path)?;
+    let target = run_forked(&original, timeout, depth)?;
+    match target.outcome {
+        Outcome::Ok => {
+            println!("tmin: {} does not crash, skipped", path.display());
+            return Ok(());
+        }
+        // Every accepted candidate would cost a full timeout
+        Outcome::Timeout => {
+            println!("tmin: {} is a timeout, skipped", path.display());
+            return Ok(());
+        }
+        Outcome::Signal(_) | Outcome::Exit(_) => (),
+    }
+
+    let mut tmin = Minimizer {
+        target,
+        timeout,
+        depth,
+        execs: 1,
+    };
+    let mut input = original.clone();
+
+    // Remove blocks, from half of the input down to single bytes
+    let mut block = (input.len() / 2).max(1);
+    loop {
+        let mut pos = 0;
+        while pos < input.len() {
+            let end = (pos + block).min(input.len());
+            let mut candidate = input[..pos].to_vec();
+            candidate.extend_from_slice(&input[end..]);
+            if !candidate.is_empty() && tmin.same_crash(&candidate)? {
+                input = candidate;
+            } else {
+                pos += block;
+            }
+        }
+        if block == 1 {
+            break;
+        }
+        block /= 2;
+    }
+
+    // Normalize the remaining bytes to '0' where possible, so the ones that matter stand out
+    for i in 0..input.len() {
+        if input[i] == b'0' {
+            continue;
+        }
+        let mut candidate = input.clone();
+        candidate[i] = b'0';
+        if tmin.same_crash(&candidate)? {
+            input = candidate;
+        }
+    }
+
+    let min_path = PathBuf::from(format!("{}.min", path.display()));
+    fs::write(&min_path, &input)?;
+    let hash = tmin.target.stack_hash.map_or_else(
+        || "no stack hash".into(),
+        |hash| format!("stack hash {hash:016x}"),
+    );
+    println!(
+        "tmin: {} -> {} ({} -> {} bytes, {:?}, {hash}, {} execs)",
+        path.display(),
+        min_path.display(),
+        original.len(),
+        input.len(),
+        tmin.target.outcome,
+        tmin.execs
+    );
+    Ok(())
+}
+
+struct Minimizer {
+    target: CrashId,
+    timeout: Duration,
+    depth: usize,
+    execs: usize,
+}
+
+impl Minimizer {
+    fn same_crash(&mut self, input: &[u8]) -> Result<bool, Error> {
+        self.execs += 1;
+        Ok(run_forked(input, self.timeout, self.depth)? == self.target)
+    }
+}
+
+/// The write end of the pipe to the parent, in the child
+static HASH_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Number of frames the child hashes
+static HASH_DEPTH: AtomicUsize = AtomicUsize::new(dedup::DEFAULT_DEPTH);
+
+/// Sends the stack hash of the crash to the parent, then dies of `signal`
+extern "C" fn tmin_crash_handler(signal: c_int) {
+    let fd = HASH_PIPE.swap(-1, Ordering::SeqCst);
+    if fd >= 0 {
+        let frames: Vec<String> = dedup::crash_frames("tmin_crash_handler")
+            .into_iter()
+            .take(HASH_DEPTH.load(Ordering::SeqCst))
+            .collect();
+        if let Some(hash) = dedup::stack_hash(&frames) {
+            let bytes = hash.to_le_bytes();
+            unsafe { libc::write(fd, bytes.as_ptr().cast(), bytes.len()) };
+        }
+    }
+    // The handler was reset on entry (`SA_RESETHAND`), this ends the child
+    unsafe { libc::raise(signal) };
+}
+
+/// In the child: the crash signals the executor of the fuzzer handles go to [`tmin_crash_handler`]
+fn install_crash_handler(fd: c_int, depth: usize) {
+    HASH_PIPE.store(fd, Ordering::SeqCst);
+    HASH_DEPTH.store(depth, Ordering::SeqCst);
+    for signal in [
+        libc::SIGABRT,
+        libc::SIGSEGV,
+        libc::SIGBUS,
+        libc::SIGILL,
+        libc::SIGFPE,
+    ] {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = tmin_crash_handler as extern "C" fn(c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESETHAND;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, ptr::null_mut());
+        }
+    }
+}
+
+/// Run the harness on `input` in a forked child, with the stack hash of its crash
+fn run_forked(input: &[u8], timeout: Duration, depth: usize) -> Result<CrashId, Error> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error().into()),
+        0 => unsafe {
+            libc::close(fds[0]);
+            // The sanitizer report of every candidate would flood the terminal
+            let null = libc::open(c"/dev/null".as_ptr(), libc::O_WRONLY);
+            if null >= 0 {
+                libc::dup2(null, libc::STDERR_FILENO);
+            }
+            install_crash_handler(fds[1], depth);
+            libfuzzer_test_one_input(input);
+            libc::_exit(0)
+        },
+        pid => {
+            unsafe { libc::close(fds[1]) };
+            let mut pipe = unsafe { File::from_raw_fd(fds[0]) };
+            let reader = thread::spawn(move || {
+                let mut out = Vec::new();
+                let _ = pipe.read_to_end(&mut out);
+                out
+            });
+
+            let start = Instant::now();
+            let mut status = 0;
+            let outcome = loop {
+                let ret = unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) };
+                if ret == pid {
+                    break if libc::WIFSIGNALED(status) {
+                        Outcome::Signal(libc::WTERMSIG(status))
+                    } else if libc::WEXITSTATUS(status) != 0 {
+                        Outcome::Exit(libc::WEXITSTATUS(status))
+                    } else {
+                        Outcome::Ok
+                    };
+                }
+                if ret == -1 {
+                    return Err(io::Error::last_os_error().into());
+                }
+                if start.elapsed() > timeout {
+                    unsafe {
+                        libc::kill(pid, libc::SIGKILL);
+                        libc::waitpid(pid, &mut status, 0);
+                    }
+                    break Outcome::Timeout;
+                }
+                thread::sleep(Duration::from_millis(1));
+            };
+
+            let hash = reader.join().unwrap_or_default();
+            Ok(CrashId {
+                outcome,
+                stack_hash: hash.try_into().ok().map(u64::from_le_bytes),
+            })
+        }
+    }
+}
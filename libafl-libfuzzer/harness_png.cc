@@ -20,6 +20,17 @@
 
 #include <vector>
 
+// The input is written by the (uninstrumented) fuzzer, MSan would see it as uninitialized
+#if defined(__has_feature)
+#if __has_feature(memory_sanitizer)
+#include <sanitizer/msan_interface.h>
+#define UNPOISON_INPUT(data, size) __msan_unpoison(data, size)
+#endif
+#endif
+#ifndef UNPOISON_INPUT
+#define UNPOISON_INPUT(data, size)
+#endif
+
 #define PNG_INTERNAL
 #include "png.h"
 
@@ -82,6 +93,7 @@ static const int kPngHeaderSize = 8;
 // Roughly follows the libpng book example:
 // http://www.libpng.org/pub/png/book/chapter13.html
 extern "C" int LLVMFuzzerTestOneInput(const uint8_t *data, size_t size) {
+  UNPOISON_INPUT(data, size);
   if (size < kPngHeaderSize) { return 0; }
 
   std::vector<unsigned char> v(data, data + size);
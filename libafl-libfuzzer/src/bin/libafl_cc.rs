@@ -1,6 +1,6 @@
 use std::env;
 
-use libafl_cc::{ClangWrapper, CompilerWrapper, ToolWrapper};
+use libafl_cc::{ClangWrapper, CompilerWrapper, LLVMPasses, ToolWrapper};
 
 pub fn main() {
     let args: Vec<String> = env::args().collect();
@@ -17,17 +17,50 @@ pub fn main() {
         dir.pop();
 
         let mut cc = ClangWrapper::new();
-        if let Some(code) = cc
-            .cpp(is_cpp)
+        cc.cpp(is_cpp)
             // silence the compiler wrapper output, needed for some configure scripts.
             .silence(true)
             .parse_args(&args)
             .expect("Failed to parse the command line")
             .link_staticlib(&dir, "libfuzzer_libpng")
-            .add_arg("-fsanitize-coverage=trace-pc-guard")
-            .run()
-            .expect("Failed to run the wrapped compiler")
-        {
+            .add_arg("-fsanitize-coverage=trace-pc-guard");
+
+        // Sanitizer of this build, its runtime options are set by the fuzzer (see lab_common::sanitizers)
+        let sanitizer = env::var("LIBAFL_SANITIZER").unwrap_or_else(|_| "none".into());
+        let sanitizer_flags: &[&str] = match sanitizer.as_str() {
+            "address" => &["-fsanitize=address"],
+            "undefined" => &["-fsanitize=undefined"],
+            "memory" => &["-fsanitize=memory", "-fsanitize-memory-track-origins"],
+            "none" => &[],
+            other => panic!(
+                "Unknown LIBAFL_SANITIZER {other:?}, expected address, undefined, memory or none"
+            ),
+        };
+        cc.add_args(sanitizer_flags);
+
+        // The tracing build for the CmpLog stages also logs every comparison
+        if env::var("LIBAFL_CMPLOG").is_ok_and(|val| val == "1") {
+            cc.add_arg("-fsanitize-coverage=trace-cmp");
+        }
+
+        // Embed the constants of compared operands, read back by `autotokens()` at startup
+        if env::var("LIBAFL_AUTOTOKENS").is_ok_and(|val| val == "1") {
+            cc.add_pass(LLVMPasses::AutoTokens);
+        }
+
+        // Source-level coverage build for `--coverage`, `-rdynamic` lets replay find the profile runtime
+        if env::var("LIBAFL_COVERAGE").is_ok_and(|val| val == "1") {
+            cc.add_arg("-fprofile-instr-generate")
+                .add_arg("-fcoverage-mapping")
+                .add_link_arg("-rdynamic");
+        }
+
+        // The forkserver build exports its symbols, for the frames of a crashing child (`dladdr`)
+        if cfg!(feature = "forkserver") {
+            cc.add_link_arg("-rdynamic");
+        }
+
+        if let Some(code) = cc.run().expect("Failed to run the wrapped compiler") {
             std::process::exit(code);
         }
     } else {
@@ -0,0 +1,362 @@
+//! Structurally valid PNG files from nothing.
+//!
+//! [`PngGenerator`] picks random dimensions, a color type with one of its allowed bit depths,
+//! the interlace method and a set of ancillary chunks, and zlib-compresses random scanlines
+//! into IDAT. It bootstraps the corpus when there are no seeds, and [`GeneratorStage`]
+//! keeps injecting fresh inputs while fuzzing.
+use std::{io::Write, marker::PhantomData};
+
+use flate2::{write::ZlibEncoder, Compression};
+use libafl::{
+    executors::Executor,
+    fuzzer::Evaluator,
+    generators::Generator,
+    inputs::{BytesInput, UsesInput},
+    stages::Stage,
+    state::{HasRand, UsesState},
+    Error,
+};
+use libafl_bolts::rands::Rand;
+
+use crate::png::{random_data, Chunk, Png};
+
+/// Color types with their allowed bit depths and the number of channels
+const COLOR_TYPES: &[(u8, &[u8], usize)] = &[
+    (0, &[1, 2, 4, 8, 16], 1), // grayscale
+    (2, &[8, 16], 3),          // RGB
+    (3, &[1, 2, 4, 8], 1),     // palette
+    (4, &[8, 16], 2),          // grayscale + alpha
+    (6, &[8, 16], 4),          // RGBA
+];
+
+/// Start and step of the 7 Adam7 passes: (x0, y0, dx, dy)
+const ADAM7: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// Generates PNG files of up to `max_dimension` x `max_dimension` pixels
+#[derive(Debug, Clone)]
+pub struct PngGenerator {
+    max_dimension: u32,
+}
+
+impl PngGenerator {
+    pub fn new(max_dimension: u32) -> Self {
+        Self {
+            max_dimension: max_dimension.max(1),
+        }
+    }
+}
+
+impl<S> Generator<BytesInput, S> for PngGenerator
+where
+    S: HasRand,
+{
+    fn generate(&mut self, state: &mut S) -> Result<BytesInput, Error> {
+        let rand = state.rand_mut();
+        let (color_type, depths, channels) = COLOR_TYPES[rand.below(COLOR_TYPES.len())];
+        let depth = depths[rand.below(depths.len())];
+        let width = 1 + rand.below(self.max_dimension as usize) as u32;
+        let height = 1 + rand.below(self.max_dimension as usize) as u32;
+        let interlace = u8::from(rand.coinflip(0.3));
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&width.to_be_bytes());
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        ihdr.extend_from_slice(&[depth, color_type, 0, 0, interlace]);
+
+        let mut png = Png::default();
+        png.chunks.push(Chunk::new(*b"IHDR", ihdr));
+
+        // Before PLTE
+        if rand.coinflip(0.3) {
+            png.chunks
+                .push(Chunk::new(*b"gAMA", 45455_u32.to_be_bytes().to_vec()));
+        }
+        if rand.coinflip(0.2) {
+            png.chunks.push(Chunk::new(*b"cHRM", random_data(rand, 32)));
+        }
+        if rand.coinflip(0.2) {
+            png.chunks
+                .push(Chunk::new(*b"sRGB", vec![rand.below(4) as u8]));
+        } else if rand.coinflip(0.2) {
+            let profile_len = 128 + rand.below(256);
+            let profile = random_data(rand, profile_len);
+            let level = rand.below(10) as u32;
+            let mut iccp = b"ICC profile\0\0".to_vec();
+            iccp.extend(compress(&profile, level)?);
+            png.chunks.push(Chunk::new(*b"iCCP", iccp));
+        }
+        if rand.coinflip(0.2) {
+            let significant = (0..channels + usize::from(color_type == 3) * 2)
+                .map(|_| 1 + rand.below(depth.min(8) as usize) as u8)
+                .collect();
+            png.chunks.push(Chunk::new(*b"sBIT", significant));
+        }
+
+        // Required for palette images, a suggestion for the truecolor ones
+        let mut palette_entries = 0;
+        if color_type == 3 || (color_type & 2 != 0 && rand.coinflip(0.1)) {
+            let max_entries = if color_type == 3 { 1 << depth } else { 256 };
+            palette_entries = 1 + rand.below(max_entries);
+            png.chunks
+                .push(Chunk::new(*b"PLTE", random_data(rand, 3 * palette_entries)));
+        }
+
+        // After PLTE, before IDAT
+        if rand.coinflip(0.2) {
+            let len = match color_type {
+                3 => 1,
+                0 | 4 => 2,
+                _ => 6,
+            };
+            png.chunks
+                .push(Chunk::new(*b"bKGD", random_data(rand, len)));
+        }
+        if palette_entries > 0 && rand.coinflip(0.1) {
+            png.chunks
+                .push(Chunk::new(*b"hIST", random_data(rand, 2 * palette_entries)));
+        }
+        if rand.coinflip(0.2) {
+            let len = match color_type {
+                0 => Some(2),
+                2 => Some(6),
+                3 => Some(1 + rand.below(palette_entries)),
+                _ => None,
+            };
+            if let Some(len) = len {
+                png.chunks
+                    .push(Chunk::new(*b"tRNS", random_data(rand, len)));
+            }
+        }
+        if rand.coinflip(0.2) {
+            let mut phys = random_data(rand, 8);
+            phys.push(rand.below(2) as u8);
+            png.chunks.push(Chunk::new(*b"pHYs", phys));
+        }
+
+        // The image data, split over one or more IDAT chunks
+        let bits_per_pixel = channels * depth as usize;
+        let mut raw = Vec::new();
+        let passes: &[(u32, u32, u32, u32)] = if interlace == 1 {
+            &ADAM7
+        } else {
+            &[(0, 0, 1, 1)]
+        };
+        for &(x0, y0, dx, dy) in passes {
+            let pass_width = width.saturating_sub(x0).div_ceil(dx) as usize;
+            let pass_height = height.saturating_sub(y0).div_ceil(dy);
+            if pass_width == 0 || pass_height == 0 {
+                continue;
+            }
+            let row_len = (pass_width * bits_per_pixel).div_ceil(8);
+            for _ in 0..pass_height {
+                raw.push(rand.below(5) as u8); // filter type
+                raw.extend(random_data(rand, row_len));
+            }
+        }
+        let level = rand.below(10) as u32;
+        let idat = compress(&raw, level)?;
+        let parts = 1 + rand.below(3);
+        let part_len = idat.len().div_ceil(parts).max(1);
+        for part in idat.chunks(part_len) {
+            png.chunks.push(Chunk::new(*b"IDAT", part.to_vec()));
+        }
+
+        // Anywhere
+        if rand.coinflip(0.2) {
+            let time = [
+                0x07,
+                0xe0 + rand.below(16) as u8,
+                1 + rand.below(12) as u8,
+                1 + rand.below(31) as u8,
+                rand.below(24) as u8,
+                rand.below(60) as u8,
+                rand.below(61) as u8,
+            ];
+            png.chunks.push(Chunk::new(*b"tIME", time.to_vec()));
+        }
+        if rand.coinflip(0.3) {
+            let text_len = rand.below(64);
+            let text = random_data(rand, text_len);
+            let level = rand.below(10) as u32;
+            match rand.below(3) {
+                0 => {
+                    let mut chunk = b"Comment\0".to_vec();
+                    chunk.extend(text);
+                    png.chunks.push(Chunk::new(*b"tEXt", chunk));
+                }
+                1 => {
+                    let mut chunk = b"Comment\0\0".to_vec();
+                    chunk.extend(compress(&text, level)?);
+                    png.chunks.push(Chunk::new(*b"zTXt", chunk));
+                }
+                _ => {
+                    let mut chunk = b"Comment\0\0\0en\0Comment\0".to_vec();
+                    chunk.extend(text);
+                    png.chunks.push(Chunk::new(*b"iTXt", chunk));
+                }
+            }
+        }
+
+        png.chunks.push(Chunk::new(*b"IEND", Vec::new()));
+        Ok(BytesInput::new(png.to_bytes()))
+    }
+}
+
+/// zlib stream of `data`, level 0 writes stored blocks
+fn compress(data: &[u8], level: u32) -> Result<Vec<u8>, Error> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Evaluates a freshly generated input every `every` runs of the stages
+#[derive(Debug)]
+pub struct GeneratorStage<G, E, EM, Z> {
+    generator: G,
+    every: u64,
+    calls: u64,
+    phantom: PhantomData<(E, EM, Z)>,
+}
+
+impl<G, E, EM, Z> GeneratorStage<G, E, EM, Z> {
+    /// A stage that never injects anything if `every` is 0
+    pub fn new(generator: G, every: u64) -> Self {
+        Self {
+            generator,
+            every,
+            calls: 0,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<G, E, EM, Z> UsesState for GeneratorStage<G, E, EM, Z>
+where
+    Z: UsesState,
+{
+    type State = Z::State;
+}
+
+impl<G, E, EM, Z> Stage<E, EM, Z> for GeneratorStage<G, E, EM, Z>
+where
+    G: Generator<<Z::State as UsesInput>::Input, Z::State>,
+    E: Executor<EM, Z> + UsesState<State = Z::State>,
+    EM: UsesState<State = Z::State>,
+    Z: Evaluator<E, EM>,
+    Z::State: HasRand,
+{
+    fn perform(
+        &mut self,
+        fuzzer: &mut Z,
+        executor: &mut E,
+        state: &mut Z::State,
+        manager: &mut EM,
+    ) -> Result<(), Error> {
+        if self.every == 0 {
+            return Ok(());
+        }
+        self.calls += 1;
+        if self.calls % self.every != 0 {
+            return Ok(());
+        }
+        let input = self.generator.generate(state)?;
+        // Only kept if it is interesting, like any other input
+        fuzzer.evaluate_input(state, executor, manager, input)?;
+        Ok(())
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+    use libafl::{
+        generators::Generator,
+        inputs::{BytesInput, HasMutatorBytes},
+        state::NopState,
+    };
+
+    use super::{PngGenerator, ADAM7, COLOR_TYPES};
+    use crate::png::Png;
+
+    #[test]
+    fn generated_files_are_valid_pngs() {
+        let max_dimension = 16;
+        let mut generator = PngGenerator::new(max_dimension);
+        let mut state = NopState::<BytesInput>::new();
+        for _ in 0..200 {
+            let input = generator.generate(&mut state).unwrap();
+            let png = Png::parse(input.bytes()).expect("no PNG signature");
+            assert!(png.trailer.is_empty());
+
+            let kinds: Vec<_> = png.chunks.iter().map(|chunk| &chunk.kind).collect();
+            assert_eq!(kinds.first(), Some(&b"IHDR"));
+            assert_eq!(kinds.last(), Some(&b"IEND"));
+            let ihdr = &png.chunks[0].data;
+            assert_eq!(ihdr.len(), 13);
+            let width = u32::from_be_bytes(ihdr[0..4].try_into().unwrap());
+            let height = u32::from_be_bytes(ihdr[4..8].try_into().unwrap());
+            let (depth, color_type, interlace) = (ihdr[8], ihdr[9], ihdr[12]);
+            assert!((1..=max_dimension).contains(&width));
+            assert!((1..=max_dimension).contains(&height));
+            let &(_, depths, channels) = COLOR_TYPES
+                .iter()
+                .find(|(kind, _, _)| *kind == color_type)
+                .expect("unknown color type");
+            assert!(depths.contains(&depth));
+            // Required for the palette images, not allowed for the grayscale ones
+            let has_palette = kinds.contains(&b"PLTE");
+            if color_type == 3 {
+                assert!(has_palette);
+            } else if color_type & 2 == 0 {
+                assert!(!has_palette);
+            }
+
+            // The IDAT chunks together inflate to the scanlines of every (interlace) pass
+            let idat: Vec<u8> = png
+                .chunks
+                .iter()
+                .filter(|chunk| &chunk.kind == b"IDAT")
+                .flat_map(|chunk| chunk.data.clone())
+                .collect();
+            let mut raw = Vec::new();
+            ZlibDecoder::new(idat.as_slice())
+                .read_to_end(&mut raw)
+                .unwrap();
+            let passes: &[(u32, u32, u32, u32)] = if interlace == 1 {
+                &ADAM7
+            } else {
+                &[(0, 0, 1, 1)]
+            };
+            let expected: usize = passes
+                .iter()
+                .map(|&(x0, y0, dx, dy)| {
+                    let pass_width = width.saturating_sub(x0).div_ceil(dx) as usize;
+                    let pass_height = height.saturating_sub(y0).div_ceil(dy) as usize;
+                    if pass_width == 0 {
+                        return 0;
+                    }
+                    pass_height * (1 + (pass_width * channels * depth as usize).div_ceil(8))
+                })
+                .sum();
+            assert_eq!(raw.len(), expected);
+        }
+    }
+}
@@ -1,213 +1,607 @@
 //! A libfuzzer-like fuzzer with llmp-multithreading support and restarts
 //! The example harness is built for libpng.
-use core::time::Duration;
 #[cfg(feature = "crash")]
 use std::ptr;
-use std::{env, path::PathBuf};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
 
 use libafl::{
     corpus::{Corpus, InMemoryOnDiskCorpus, OnDiskCorpus},
-    events::{setup_restarting_mgr_std, EventConfig, EventRestarter},
-    executors::{inprocess::InProcessExecutor, ExitKind},
-    feedback_or, feedback_or_fast,
-    feedbacks::{CrashFeedback, MaxMapFeedback, TimeFeedback, TimeoutFeedback},
+    events::{launcher::Launcher, EventConfig, EventRestarter, LlmpRestartingEventManager},
+    executors::{forkserver::ForkserverExecutor, inprocess::InProcessExecutor, ExitKind},
+    feedback_and_fast, feedback_or,
+    feedbacks::{CrashFeedback, MaxMapFeedback, TimeFeedback},
     fuzzer::{Fuzzer, StdFuzzer},
     inputs::{BytesInput, HasTargetBytes},
-    monitors::MultiMonitor,
     mutators::{
         scheduled::{havoc_mutations, tokens_mutations, StdScheduledMutator},
-        token_mutations::Tokens,
+        token_mutations::{I2SRandReplace, Tokens},
     },
     observers::{CanTrack, HitcountsMapObserver, StdMapObserver, TimeObserver},
-    schedulers::{
-        powersched::PowerSchedule, IndexesLenTimeMinimizerScheduler, StdWeightedScheduler,
+    schedulers::{IndexesLenTimeMinimizerScheduler, StdWeightedScheduler},
+    stages::{
+        calibrate::CalibrationStage, power::StdPowerMutationalStage, IfStage, StdMutationalStage,
+        TracingStage,
     },
-    stages::{calibrate::CalibrationStage, power::StdPowerMutationalStage},
-    state::{HasCorpus, StdState},
+    state::{HasCorpus, HasMaxSize, StdState},
     Error, HasMetadata,
 };
 use libafl_bolts::{
+    core_affinity::CoreId,
+    current_time,
     rands::StdRand,
+    shmem::{ShMemProvider, StdShMemProvider, UnixShMemProvider},
     tuples::{tuple_list, Merge},
-    AsSlice,
+    AsSlice, AsSliceMut,
+};
+use libafl_targets::{
+    libfuzzer_initialize, libfuzzer_test_one_input, CmpLogMap, CmpLogObserver, EDGES_MAP,
+    MAX_EDGES_FOUND,
 };
-use libafl_targets::{libfuzzer_initialize, libfuzzer_test_one_input, EDGES_MAP, MAX_EDGES_FOUND};
 use mimalloc::MiMalloc;
 
-// pengganti malloc atau jmalloc yang lebih efisien
+use lab_common::{
+    afl_output::{AflCorpus, AflNameFeedback, AflOutput, AflStatsStage},
+    cmin::{self, EdgesObserver},
+    coverage,
+    dedup::{StackHashObserver, UniqueCrashFeedback},
+    forkserver::{self, ChildCrashInfo},
+    hangs::{HangFeedback, HangStage},
+    monitor::FuzzerMonitor,
+    replay,
+    seed::{self, DeterministicScheduler, SeedMetadata},
+    sync::{SyncOriginFeedback, SyncStage},
+    tmin,
+};
+
+mod generator;
+mod libfuzzer;
+mod options;
+mod png;
+mod tokens;
+use generator::{GeneratorStage, PngGenerator};
+use libfuzzer::{ArtifactNameFeedback, LimitStage};
+use options::FuzzerOptions;
+
+/// Number of generated inputs the corpus starts with when there are no seeds
+const GENERATED_SEEDS: usize = 16;
+
+/// Width and height limit of the generated PNG files
+const GENERATED_MAX_DIMENSION: u32 = 64;
+
+// A more efficient allocator than malloc or jemalloc
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+/// The edges map of the harness, for the modes that only run inputs
+#[cfg(not(test))]
+fn edges_observer() -> EdgesObserver {
+    unsafe {
+        HitcountsMapObserver::new(StdMapObserver::from_mut_ptr(
+            "edges",
+            EDGES_MAP.as_mut_ptr(),
+            MAX_EDGES_FOUND,
+        ))
+    }
+}
+
 /// The main fn, `no_mangle` as it is a C main
 #[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn libafl_main() {
-    // Hanya dibutuhkan di no_std
+    // Registry the metadata types used in this fuzzer
+    // Needed only on no_std
     // unsafe { RegistryBuilder::register::<Tokens>(); }
 
+    // Started by the ForkserverExecutor of a `--forkserver` client
+    if env::var_os(forkserver::TARGET_ENV).is_some() {
+        forkserver::run_target();
+    }
+
+    let args: Vec<String> = env::args().collect();
+    let options = FuzzerOptions::parse(&args).expect("Invalid fuzzer configuration");
+
     println!(
         "Workdir: {:?}",
         env::current_dir().unwrap().to_string_lossy().to_string()
     );
-    fuzz(
-        &[PathBuf::from("./seeds-png")],
-        PathBuf::from("./corpus"),
-        PathBuf::from("./crashes"),
-        1337,
-    )
-    .expect("An error occurred while fuzzing");
+
+    if let Some(output_dir) = &options.cmin {
+        cmin::minimize(&options.input, output_dir, options.timeout, edges_observer)
+            .expect("An error occurred while minimizing the corpus");
+        return;
+    }
+
+    if !options.tmin.is_empty() {
+        tmin::minimize_crashes(&options.tmin, options.timeout, options.crash_depth)
+            .expect("An error occurred while minimizing the crashes");
+        return;
+    }
+
+    if !options.replay.is_empty() {
+        let clean = replay::replay(&options.replay, options.timeout, None, edges_observer)
+            .expect("An error occurred while replaying the inputs");
+        if !clean {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(output_dir) = &options.coverage {
+        coverage::report(
+            &options.coverage_corpus,
+            options.coverage_base.as_deref(),
+            output_dir,
+            options.timeout,
+            edges_observer,
+        )
+        .expect("An error occurred while measuring the coverage");
+        return;
+    }
+
+    // Given only files, run them once like libFuzzer does
+    if !options.files.is_empty() {
+        libfuzzer::run_files(&options.files).expect("An error occurred while running the inputs");
+        return;
+    }
+
+    fuzz(&options).expect("An error occurred while fuzzing");
 }
 
-/// The actual fuzzer
+/// The actual fuzzer, one client per core of `--cores`
 #[cfg(not(test))]
-fn fuzz(corpus_dirs: &[PathBuf], corp_dir: PathBuf, objective_dir: PathBuf, broker_port: u16) -> Result<(), Error> {
+fn fuzz(options: &FuzzerOptions) -> Result<(), Error> {
+    if options.forkserver {
+        forkserver::check_build()?;
+    }
+    let shmem_provider = StdShMemProvider::new().expect("Failed to init shared memory");
+
     // 'While the stats are state, they are usually used in the broker - which is likely never restarted
-    let monitor = MultiMonitor::new(|s| println!("{s}"));
-
-    // The restarting state will spawn the same process again as child, then restarted it each time it crashes.
-    let (state, mut restarting_mgr) =
-        match setup_restarting_mgr_std(monitor, broker_port, EventConfig::AlwaysUnique) {
-            Ok(res) => res,
-            Err(err) => match err {
-                Error::ShuttingDown => {
-                    return Ok(());
-                }
-                _ => {
-                    panic!("Failed to setup the restarter: {err}");
-                }
-            },
+    let monitor = FuzzerMonitor::new(options.tui, "fuzzer_libpng");
+    // The output of the clients would break the terminal UI
+    let quiet_clients = monitor.is_tui() && options.stdout.is_none();
+
+    // The same for all clients, they derive their own seed from it
+    let seed = seed::campaign_seed(options.seed);
+    println!("Seed: {seed} (repeat the campaign with --seed {seed})");
+
+    // One merged dictionary for the campaign, next to its output directory
+    let dict_path =
+        tokens::persisted_dict_path(options.afl_out.as_deref().unwrap_or(&options.corpus));
+
+    // `--max-total-time` counts from the start of the campaign, not of a client
+    let deadline = options.max_total_time.map(|max| current_time() + max);
+
+    // Each client is restarted by its own restarter each time it crashes
+    let mut run_client = |state: Option<_>,
+                          mut restarting_mgr: LlmpRestartingEventManager<_, _, _>,
+                          core_id: CoreId| {
+        if let Some(prefix) = &options.stdout {
+            redirect_output(prefix, core_id)?;
+        }
+
+        // With `--afl-out` the corpora of this client live in the AFL++ layout
+        let afl_output = options
+            .afl_out
+            .as_deref()
+            .map(|out| AflOutput::create(out, &format!("core{}", core_id.0), "fuzzer_libpng"))
+            .transpose()?;
+        let (corpus_dir, crashes_dir, hangs_dir) = match &afl_output {
+            Some(output) => (output.queue(), output.crashes(), output.hangs()),
+            None => (
+                options.corpus.clone(),
+                options.crashes.clone(),
+                options.hangs.clone(),
+            ),
         };
 
-    // Create an observation channel using the coverage map
-    let edges_observer = unsafe {
-        HitcountsMapObserver::new(StdMapObserver::from_mut_ptr(
-            "edges",
-            EDGES_MAP.as_mut_ptr(),
-            MAX_EDGES_FOUND,
-        ))
-        .track_indices()
-    };
+        // With `--forkserver` the coverage map is shared with the target process
+        let mut forkserver_shmem_provider = UnixShMemProvider::new()?;
+        let mut coverage_shmem = if options.forkserver {
+            Some(forkserver::coverage_map(&mut forkserver_shmem_provider)?)
+        } else {
+            None
+        };
 
-    // Create an observation channel to keep track of the execution time
-    let time_observer = TimeObserver::new("time");
+        // Create an observation channel using the coverage map
+        let edges_observer = unsafe {
+            let (map, len) = match &mut coverage_shmem {
+                Some(shmem) => (shmem.as_slice_mut().as_mut_ptr(), forkserver::MAP_SIZE),
+                None => (EDGES_MAP.as_mut_ptr(), MAX_EDGES_FOUND),
+            };
+            HitcountsMapObserver::new(StdMapObserver::from_mut_ptr("edges", map, len))
+                .track_indices()
+        };
 
-    let map_feedback = MaxMapFeedback::new(&edges_observer);
+        // Create an observation channel to keep track of the execution time
+        let time_observer = TimeObserver::new("time");
 
-    let calibration = CalibrationStage::new(&map_feedback);
+        // Create an observation channel for the comparisons logged by the `trace-cmp` build
+        let cmplog_observer = CmpLogObserver::new("cmplog", true);
 
-    // Feedback to rate the interestingness of an input
-    // This one is composed by two Feedbacks in OR
-    let mut feedback = feedback_or!(
-        // New maximization map feedback linked to the edges observer and the feedback state
-        map_feedback,
-        // Time feedback, this one does not need a feedback state
-        TimeFeedback::new(&time_observer)
-    );
+        let map_feedback = MaxMapFeedback::new(&edges_observer);
 
-    // A feedback to choose if an input is a solution or not
-    let mut objective = feedback_or_fast!(CrashFeedback::new(), TimeoutFeedback::new());
-
-    // If not restarting, create a State from scratch
-    let mut state = state.unwrap_or_else(|| {
-        StdState::new(
-            // RNG
-            StdRand::new(),
-            InMemoryOnDiskCorpus::new(corp_dir).unwrap(),
-            OnDiskCorpus::new(objective_dir).unwrap(),
-            &mut feedback,
-            &mut objective,
-        )
-        .unwrap()
-    });
-
-    println!("We're a client, let's fuzz :)");
-
-    // Create a PNG dictionary if not existing
-    if state.metadata_map().get::<Tokens>().is_none() {
-        state.add_metadata(Tokens::from([
-            vec![137, 80, 78, 71, 13, 10, 26, 10], // PNG header
-            "IHDR".as_bytes().to_vec(),
-            "IDAT".as_bytes().to_vec(),
-            "PLTE".as_bytes().to_vec(),
-            "IEND".as_bytes().to_vec(),
-        ]));
-    }
+        // Only names the map for the calibration stage, built with the executor below
+        let calibration_feedback = MaxMapFeedback::new(&edges_observer);
 
-    // Setup a basic mutator with a mutational stage
-    let mutator = StdScheduledMutator::new(havoc_mutations().merge(tokens_mutations()));
-    let power = StdPowerMutationalStage::new(mutator);
-    let mut stages = tuple_list!(calibration, power);
+        // Feedback to rate the interestingness of an input
+        // This one is composed by two Feedbacks in OR
+        let mut feedback = feedback_or!(
+            // New maximization map feedback linked to the edges observer and the feedback state
+            map_feedback,
+            // Time feedback, this one does not need a feedback state
+            TimeFeedback::new(&time_observer),
+            // AFL++ file names with `--afl-out`
+            AflNameFeedback::new(AflCorpus::Queue, afl_output.is_some()),
+            // The origin of the inputs of `--sync-dir`
+            SyncOriginFeedback::new()
+        );
 
-    // A minimization+queue policy to get testcasess from the corpus
-    let scheduler = IndexesLenTimeMinimizerScheduler::new(
-        &edges_observer,
-        StdWeightedScheduler::with_schedule(&mut state, &edges_observer, Some(PowerSchedule::FAST)),
-    );
+        // Create an observation channel for the stack hash of a crash,
+        // the hashes seen are shared by all clients
+        let stack_observer = StackHashObserver::new("stack", options.crash_depth);
+        let hashes_dir = options.afl_out.as_deref().unwrap_or(&options.crashes);
+
+        // A feedback to choose if an input is a solution or not,
+        // a crash only if its stack hash was not seen before.
+        // Timeouts are no solutions, the ones confirmed by the hang stage are stored in the hangs directory
+        let mut objective = feedback_or!(
+            feedback_and_fast!(
+                CrashFeedback::new(),
+                UniqueCrashFeedback::new(&stack_observer, hashes_dir)?
+            ),
+            HangFeedback::new(&time_observer, options.timeout),
+            // `<--crash-prefix><hash>`, the AFL++ names win with `--afl-out`
+            ArtifactNameFeedback::new(
+                options
+                    .crash_prefix
+                    .clone()
+                    .filter(|_| afl_output.is_none())
+            ),
+            AflNameFeedback::new(AflCorpus::Crashes, afl_output.is_some()),
+            SyncOriginFeedback::new()
+        );
+
+        // If not restarting, create a State from scratch
+        let client_seed = seed::client_seed(seed, core_id);
+        let mut state = state.unwrap_or_else(|| {
+            StdState::new(
+                // RNG
+                StdRand::with_seed(client_seed),
+                InMemoryOnDiskCorpus::new(&corpus_dir).unwrap(),
+                OnDiskCorpus::new(&crashes_dir).unwrap(),
+                &mut feedback,
+                &mut objective,
+            )
+            .unwrap()
+        });
+
+        println!("We're a client, let's fuzz :)");
+
+        // A restarted client goes on with the RNG of its saved state
+        if !state.has_metadata::<SeedMetadata>() {
+            state.add_metadata(SeedMetadata { seed, client_seed });
+        }
+        let seeds = state.metadata::<SeedMetadata>()?;
+        println!("Seed: {}, client seed: {}", seeds.seed, seeds.client_seed);
+
+        if let Some(max_len) = options.max_len {
+            state.set_max_size(max_len);
+        }
 
-    // A fuzzer with feedbacks and a corpus scheduler
-    let mut fuzzer = StdFuzzer::new(scheduler, feedback, objective);
-
-    // The wrapped harness function, calling out to the LLVM-style harness
-    let mut harness = |input: &BytesInput| {
-        let target = input.target_bytes();
-        let buf = target.as_slice();
-        #[cfg(feature = "crash")]
-        if buf.len() > 4 && buf[4] == 0 {
-            unsafe {
-                eprintln!("Crashing (for testing purposes)");
-                let addr = ptr::null_mut();
-                *addr = 1;
+        // Create a PNG dictionary if not existing, merged with the tokens of the target binary,
+        // the dictionaries of earlier runs on this corpus and the ones given with `--dict`
+        if state.metadata_map().get::<Tokens>().is_none() {
+            let mut tokens = tokens::png_tokens();
+            tokens += tokens::target_tokens()?;
+            if dict_path.exists() {
+                tokens::load_dicts(&mut tokens, &[dict_path.clone()])?;
+            }
+            tokens::load_dicts(&mut tokens, &options.dict)?;
+            // The other clients merge the same tokens, only the first one writes them
+            if !options.attach && options.cores.ids.first() == Some(&core_id) {
+                tokens::write_dict(&tokens, &dict_path)?;
+                println!("Using {} tokens, saved to {:?}", tokens.len(), dict_path);
+            } else {
+                println!("Using {} tokens", tokens.len());
             }
+            state.add_metadata(tokens);
         }
-        libfuzzer_test_one_input(buf);
-        ExitKind::Ok
-    };
 
- 
-    // Buat executor untuk fungsi dalam proses dengan satu observer 
-    // untuk cakupan tepi dan satu untuk waktu eksekusi    
-    let mut executor = InProcessExecutor::with_timeout(
-        &mut harness,
-        tuple_list!(edges_observer, time_observer),
-        &mut fuzzer,
-        &mut state,
-        &mut restarting_mgr,
-        Duration::new(10, 0),
-    )?;
-    // 10 seconds timeout
-
-    // The actual target run starts here.
-    // Call LLVMFUzzerInitialize() if present.
-    let args: Vec<String> = env::args().collect();
-    if libfuzzer_initialize(&args) == -1 {
-        println!("Warning: LLVMFuzzerInitialize failed with -1");
-    }
+        // A minimization+queue policy to get testcasess from the corpus,
+        // with `--deterministic` the corpus in order
+        let scheduler = DeterministicScheduler::new(
+            IndexesLenTimeMinimizerScheduler::new(
+                &edges_observer,
+                StdWeightedScheduler::with_schedule(&mut state, &edges_observer, Some(options.schedule.into())),
+            ),
+            options.deterministic,
+        );
 
-    // In case the corpus is empty (on first run), reset
-    if state.must_load_initial_inputs() {
-        state
-            .load_initial_inputs(&mut fuzzer, &mut executor, &mut restarting_mgr, corpus_dirs)
-            .unwrap_or_else(|_| panic!("Failed to load initial corpus at {:?}", &corpus_dirs));
-        println!("We imported {} inputs from disk.", state.corpus().count());
-    }
+        // A fuzzer with feedbacks and a corpus scheduler
+        let mut fuzzer = StdFuzzer::new(scheduler, feedback, objective);
+
+        // The wrapped harness function, calling out to the LLVM-style harness
+        let mut harness = |input: &BytesInput| {
+            let target = input.target_bytes();
+            let buf = target.as_slice();
+            #[cfg(feature = "crash")]
+            if buf.len() > 4 && buf[4] == 0 {
+                unsafe {
+                    eprintln!("Crashing (for testing purposes)");
+                    let addr = ptr::null_mut();
+                    *addr = 1;
+                }
+            }
+            libfuzzer_test_one_input(buf);
+            ExitKind::Ok
+        };
+        let mut tracing_harness = harness;
+
+        // The actual target run starts here.
+        // Call LLVMFUzzerInitialize() if present.
+        let args: Vec<String> = env::args().collect();
+        if libfuzzer_initialize(&args) == -1 {
+            println!("Warning: LLVMFuzzerInitialize failed with -1");
+        }
 
+        // The stages, the initial inputs and the fuzzing loop are the same for both executors,
+        // only their types differ
+        macro_rules! fuzz_with {
+            ($executor:ident, $tracing:expr) => {{
+                // Calibration and the power schedule go by the run time, not with `--deterministic`
+                let timed = !options.deterministic;
+                let calibration = IfStage::new(
+                    move |_fuzzer, _executor, _state, _mgr| Ok(timed),
+                    tuple_list!(CalibrationStage::new(&calibration_feedback)),
+                );
 
-    // Fuzzer ini memulai kembali setelah 1 mio `fuzz_one` eksekusi.     
-    // Setiap fuzz_one akan secara internal melakukan banyak eksekusi target.     
-    // Jika target Anda sangat tidak aktif, menetapkan penghitungan rendah di sini dapat membantu.     
-    // Namun, Anda akan kehilangan banyak kinerja seperti itu.
-    let iters = 1_000_000;
-    fuzzer.fuzz_loop_for(
-        &mut stages,
-        &mut executor,
-        &mut state,
-        &mut restarting_mgr,
-        iters,
-    )?;
+                // Setup a basic mutator with a mutational stage
+                let mutator = StdScheduledMutator::new(
+                    havoc_mutations()
+                        .merge(tokens_mutations())
+                        .merge(png::png_mutations()),
+                );
+                let power = IfStage::new(
+                    move |_fuzzer, _executor, _state, _mgr| Ok(timed),
+                    tuple_list!(StdPowerMutationalStage::new(mutator)),
+                );
+                // The same mutations, as many times as the RNG says
+                let mutator = StdScheduledMutator::new(
+                    havoc_mutations()
+                        .merge(tokens_mutations())
+                        .merge(png::png_mutations()),
+                );
+                let mutational = IfStage::new(
+                    move |_fuzzer, _executor, _state, _mgr| Ok(!timed),
+                    tuple_list!(StdMutationalStage::new(mutator)),
+                );
 
-    // Penting, bahwa kami menyimpan state sebelum memulai kembali!
-    // Kalau tidak, orang tua tidak akan menanggapi anak baru dan berhenti.
-    restarting_mgr.on_restart(&mut state)?;
+                // Input-to-state replacement of the logged comparison operands
+                let i2s = StdMutationalStage::new(StdScheduledMutator::new(tuple_list!(I2SRandReplace::new())));
 
+                // Setup a tracing stage in which we log comparisons
+                let tracing = TracingStage::new($tracing);
+
+                // The CmpLog stages only run with `--cmplog`, against a `LIBAFL_CMPLOG=1` build of the target
+                let cmplog = options.cmplog;
+                let cmplog_stages = IfStage::new(
+                    move |_fuzzer, _executor, _state, _mgr| Ok(cmplog),
+                    tuple_list!(tracing, i2s),
+                );
+                // Fresh, structurally valid PNG files now and then
+                // The stages on a clock are off with `--deterministic`
+                let generate = GeneratorStage::new(
+                    PngGenerator::new(GENERATED_MAX_DIMENSION),
+                    if timed { options.generate_every } else { 0 },
+                );
+                // fuzzer_stats and plot_data with `--afl-out`
+                let afl_stats = AflStatsStage::new(afl_output.clone().filter(|_| timed));
+                // The inputs of the fuzzers in `--sync-dir`, imported by the first client only
+                let sync_dirs = if timed && options.cores.ids.first() == Some(&core_id) {
+                    options.sync_dirs.clone()
+                } else {
+                    Vec::new()
+                };
+                let sync = SyncStage::new(sync_dirs);
+                // The timeouts run again in a fresh process, the ones that still hang are stored
+                let hangs = HangStage::new(
+                    options.timeout,
+                    options.hang_timeout,
+                    &hangs_dir,
+                    afl_output.is_some(),
+                )?;
+                // `--runs` is for all clients together, each one stops after its share
+                let index = options
+                    .cores
+                    .ids
+                    .iter()
+                    .position(|id| *id == core_id)
+                    .unwrap_or_default();
+                let runs = options
+                    .runs
+                    .map(|runs| libfuzzer::client_runs(runs, options.cores.ids.len(), index));
+                let limit = LimitStage::new(runs, deadline);
+                let mut stages = tuple_list!(
+                    limit,
+                    calibration,
+                    cmplog_stages,
+                    power,
+                    mutational,
+                    generate,
+                    afl_stats,
+                    sync,
+                    hangs
+                );
+
+                // In case the corpus is empty (on first run), reset
+                if state.must_load_initial_inputs() {
+                    let seed_dirs: Vec<PathBuf> = options
+                        .input
+                        .iter()
+                        .filter(|dir| fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some()))
+                        .cloned()
+                        .collect();
+                    if seed_dirs.is_empty() {
+                        // No seeds at all, start from generated PNG files
+                        let mut generator = PngGenerator::new(GENERATED_MAX_DIMENSION);
+                        state.generate_initial_inputs_forced(
+                            &mut fuzzer,
+                            &mut $executor,
+                            &mut generator,
+                            &mut restarting_mgr,
+                            GENERATED_SEEDS,
+                        )?;
+                        println!(
+                            "No seeds in {:?}, we generated {} inputs.",
+                            &options.input,
+                            state.corpus().count()
+                        );
+                    } else {
+                        state
+                            .load_initial_inputs(&mut fuzzer, &mut $executor, &mut restarting_mgr, &seed_dirs)
+                            .unwrap_or_else(|_| panic!("Failed to load initial corpus at {:?}", &seed_dirs));
+                        println!("We imported {} inputs from disk.", state.corpus().count());
+                    }
+                }
+                if options.forkserver {
+                    forkserver::check_coverage(&state)?;
+                }
+
+
+                // This fuzzer restarts after `--iterations` (default 1 mio) `fuzz_one` executions.
+                // Each fuzz_one will internally do many executions of the target.
+                // If your target is very instable, setting a low count here may help.
+                // However, you will lose a lot of performance that way.
+                // With `--runs`/`--max-total-time` the limit stage can stop it earlier.
+                match fuzzer.fuzz_loop_for(
+                    &mut stages,
+                    &mut $executor,
+                    &mut state,
+                    &mut restarting_mgr,
+                    options.iterations,
+                ) {
+                    // Stopped for good by the limit stage
+                    Err(Error::ShuttingDown) => return Ok(()),
+                    result => result?,
+                };
+            }};
+        }
+
+        // The in-process executor of the cmplog tracing, never run without `--cmplog`
+        macro_rules! in_process_tracing {
+            () => {
+                InProcessExecutor::with_timeout(
+                    &mut tracing_harness,
+                    tuple_list!(cmplog_observer),
+                    &mut fuzzer,
+                    &mut state,
+                    &mut restarting_mgr,
+                    options.timeout * 10,
+                )?
+            };
+        }
+
+        if options.forkserver {
+            // A fresh process (fork) per input, the coverage map and the input in shared memory.
+            // The child writes the frames and the ASan report of a crash to shared memory too
+            let mut crash_shmem = forkserver::crash_info_map(&mut forkserver_shmem_provider)?;
+            let child = ChildCrashInfo::new(&mut crash_shmem);
+            let mut executor = ForkserverExecutor::builder()
+                .program(env::current_exe()?)
+                .env(forkserver::TARGET_ENV, "1")
+                .env(forkserver::CRASH_SHM_ENV, forkserver::shmem_env(&crash_shmem))
+                .shmem_provider(&mut forkserver_shmem_provider)
+                .coverage_map_size(forkserver::MAP_SIZE)
+                .timeout(options.timeout)
+                .is_persistent(false)
+                .build(tuple_list!(
+                    edges_observer,
+                    time_observer,
+                    stack_observer.with_child(child)
+                ))?;
+
+            if options.cmplog {
+                // A second forkserver for cmplog, the child writes the comparisons to shared memory
+                let mut cmplog_shmem = forkserver::cmplog_map(&mut forkserver_shmem_provider)?;
+                let cmplog_observer = unsafe {
+                    CmpLogObserver::with_map_ptr(
+                        "cmplog",
+                        cmplog_shmem.as_slice_mut().as_mut_ptr().cast::<CmpLogMap>(),
+                        true,
+                    )
+                };
+                let tracing = ForkserverExecutor::builder()
+                    .program(env::current_exe()?)
+                    .env(forkserver::TARGET_ENV, "1")
+                    .env(forkserver::CMPLOG_SHM_ENV, forkserver::shmem_env(&cmplog_shmem))
+                    .shmem_provider(&mut forkserver_shmem_provider)
+                    .timeout(options.timeout * 10)
+                    .is_persistent(false)
+                    .build(tuple_list!(cmplog_observer))?;
+                fuzz_with!(executor, tracing);
+            } else {
+                fuzz_with!(executor, in_process_tracing!());
+            }
+        } else {
+            // Create the executor for an in-process function with one observer for edge coverage
+            // and one for the execution time
+            let mut executor = InProcessExecutor::with_timeout(
+                &mut harness,
+                tuple_list!(edges_observer, time_observer, stack_observer),
+                &mut fuzzer,
+                &mut state,
+                &mut restarting_mgr,
+                options.timeout,
+            )?;
+            fuzz_with!(executor, in_process_tracing!());
+        }
+
+        // It's important, that we store the state before restarting!
+        // Else, the parent will not respond to the new child and quit.
+        restarting_mgr.on_restart(&mut state)?;
+
+        Ok(())
+    };
+
+    match Launcher::builder()
+        .shmem_provider(shmem_provider)
+        .configuration(EventConfig::AlwaysUnique)
+        .monitor(monitor)
+        .run_client(&mut run_client)
+        .cores(&options.cores)
+        .broker_port(options.broker_port)
+        // With `--attach` the clients connect to the broker already listening on the port
+        .spawn_broker(!options.attach)
+        .remote_broker_addr(options.remote_broker)
+        .stdout_file(quiet_clients.then_some("/dev/null"))
+        .build()
+        .launch()
+    {
+        Ok(()) => Ok(()),
+        Err(Error::ShuttingDown) => {
+            println!("Fuzzing stopped by user. Good bye.");
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Send the output of a client to `<prefix>.<core id>`, appending across restarts
+#[cfg(not(test))]
+fn redirect_output(prefix: &Path, core_id: CoreId) -> Result<(), Error> {
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(format!("{}.{}", prefix.display(), core_id.0))?;
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(log.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
     Ok(())
 }
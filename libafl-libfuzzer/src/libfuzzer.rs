@@ -0,0 +1,219 @@
+//! libFuzzer compatibility, so OSS-Fuzz-style scripts can drive the fuzzer unchanged.
+//!
+//! The `-flag=value` arguments are rewritten to the long options of [`crate::options`],
+//! `-jobs`/`-workers` pick the number of clients and single files are just run once.
+//! [`LimitStage`] stops the clients at `-runs` and `-max_total_time`, [`ArtifactNameFeedback`]
+//! names the crashes `<-artifact_prefix><hash>`.
+use core::time::Duration;
+use std::{borrow::Cow, env, fs, marker::PhantomData, path::PathBuf};
+
+use libafl::{
+    corpus::{Corpus, Testcase},
+    events::{EventFirer, EventRestarter},
+    executors::ExitKind,
+    feedbacks::Feedback,
+    inputs::Input,
+    observers::ObserversTuple,
+    stages::Stage,
+    state::{HasExecutions, HasSolutions, HasStartTime, State, UsesState},
+    Error,
+};
+use libafl_bolts::{current_time, Named};
+use libafl_targets::{libfuzzer_initialize, libfuzzer_test_one_input};
+
+/// Rewrite the libFuzzer flags (`-runs=10`) into the long options (`--runs=10`)
+pub fn translate_args(args: &[String]) -> Vec<String> {
+    let mut translated = Vec::with_capacity(args.len());
+    for arg in args {
+        let Some((flag, value)) = arg
+            .strip_prefix('-')
+            .filter(|flag| !flag.starts_with('-'))
+            .and_then(|flag| flag.split_once('='))
+            .filter(|(flag, _)| flag.len() > 1)
+        else {
+            translated.push(arg.clone());
+            continue;
+        };
+
+        match flag {
+            "runs" | "max_len" | "max_total_time" | "dict" | "jobs" | "workers" => {
+                translated.push(format!("--{}={value}", flag.replace('_', "-")));
+            }
+            // libFuzzer picks a random seed for `-seed=0`
+            "seed" if value == "0" => (),
+            "seed" => translated.push(format!("--seed={value}")),
+            // seconds for libFuzzer, milliseconds for us
+            "timeout" => match value.parse::<u64>() {
+                Ok(secs) => translated.push(format!("--timeout={}", secs * 1000)),
+                Err(_) => translated.push(format!("--timeout={value}")),
+            },
+            // `out/crash-`: the crashes go to `out/`, named `crash-<hash>`
+            "artifact_prefix" => {
+                let (dir, name) = match value.rsplit_once('/') {
+                    Some((dir, name)) => (format!("{dir}/"), name),
+                    None => ("./".to_string(), value),
+                };
+                translated.push(format!("--crashes={dir}"));
+                if !name.is_empty() {
+                    translated.push(format!("--crash-prefix={name}"));
+                }
+            }
+            _ => println!("WARNING: unsupported libFuzzer flag '{arg}' ignored"),
+        }
+    }
+    translated
+}
+
+/// The share of the `-runs` of the campaign done by the client `index` of `clients`
+pub fn client_runs(runs: u64, clients: usize, index: usize) -> u64 {
+    let clients = clients.max(1) as u64;
+    runs / clients + u64::from((index as u64) < runs % clients)
+}
+
+/// Names the new crashes `<prefix><hash>` like libFuzzer, never interesting by itself.
+///
+/// Combine it with `feedback_or!` in the objective, without a prefix the default names stay.
+#[derive(Debug, Clone)]
+pub struct ArtifactNameFeedback {
+    prefix: Option<String>,
+}
+
+impl ArtifactNameFeedback {
+    pub fn new(prefix: Option<String>) -> Self {
+        Self { prefix }
+    }
+}
+
+impl Named for ArtifactNameFeedback {
+    fn name(&self) -> &Cow<'static, str> {
+        static NAME: Cow<'static, str> = Cow::Borrowed("artifact_name");
+        &NAME
+    }
+}
+
+impl<S> Feedback<S> for ArtifactNameFeedback
+where
+    S: State + HasSolutions,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _input: &S::Input,
+        _observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<State = S>,
+        OT: ObserversTuple<S>,
+    {
+        Ok(false)
+    }
+
+    fn append_metadata<EM, OT>(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        _observers: &OT,
+        testcase: &mut Testcase<S::Input>,
+    ) -> Result<(), Error>
+    where
+        OT: ObserversTuple<S>,
+        EM: EventFirer<State = S>,
+    {
+        let Some(prefix) = &self.prefix else {
+            return Ok(());
+        };
+        // The hash the solutions corpus would name it by
+        let id = state.solutions().count();
+        if let Some(input) = testcase.input() {
+            *testcase.filename_mut() = Some(format!("{prefix}{}", input.generate_name(id)));
+        }
+        Ok(())
+    }
+}
+
+/// Stops the client for good once it did its `runs` or the `deadline` (the start of the
+/// campaign plus `-max_total_time`) is over, checked before every `fuzz_one`
+#[derive(Debug)]
+pub struct LimitStage<E, EM, Z> {
+    runs: Option<u64>,
+    deadline: Option<Duration>,
+    phantom: PhantomData<(E, EM, Z)>,
+}
+
+impl<E, EM, Z> LimitStage<E, EM, Z> {
+    pub fn new(runs: Option<u64>, deadline: Option<Duration>) -> Self {
+        Self {
+            runs,
+            deadline,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<E, EM, Z> UsesState for LimitStage<E, EM, Z>
+where
+    Z: UsesState,
+{
+    type State = Z::State;
+}
+
+impl<E, EM, Z> Stage<E, EM, Z> for LimitStage<E, EM, Z>
+where
+    E: UsesState<State = Z::State>,
+    EM: EventRestarter<State = Z::State>,
+    Z: UsesState,
+    Z::State: HasExecutions + HasStartTime,
+{
+    fn perform(
+        &mut self,
+        _fuzzer: &mut Z,
+        _executor: &mut E,
+        state: &mut Z::State,
+        manager: &mut EM,
+    ) -> Result<(), Error> {
+        let now = current_time();
+        let runs_done = self.runs.is_some_and(|runs| *state.executions() >= runs);
+        let time_done = self.deadline.is_some_and(|deadline| now >= deadline);
+        if !runs_done && !time_done {
+            return Ok(());
+        }
+
+        let elapsed = now.saturating_sub(*state.start_time());
+        println!(
+            "Done {} runs in {} s",
+            state.executions(),
+            elapsed.as_secs()
+        );
+        // The restarter must not spawn this client again
+        manager.send_exiting()?;
+        Err(Error::shutting_down())
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Run each file once through the harness, as libFuzzer does when given files instead of dirs
+pub fn run_files(files: &[PathBuf]) -> Result<(), Error> {
+    let args: Vec<String> = env::args().collect();
+    if libfuzzer_initialize(&args) == -1 {
+        println!("Warning: LLVMFuzzerInitialize failed with -1");
+    }
+
+    for file in files {
+        println!("Running: {}", file.display());
+        let buf = fs::read(file)?;
+        let start = current_time();
+        libfuzzer_test_one_input(&buf);
+        let elapsed = current_time() - start;
+        println!("Executed {} in {} ms", file.display(), elapsed.as_millis());
+    }
+    Ok(())
+}
@@ -0,0 +1,455 @@
+//! Command line and config file handling for the libpng fuzzer.
+//!
+//! Every setting can come from (highest priority first) the command line,
+//! a `FUZZ_LIBPNG_*` environment variable, the TOML file given with `--config`
+//! or the built-in default, so a single binary can drive many campaigns.
+//! The switches take an optional value, `--tui=false` turns off a `tui = true` of the file.
+//! The libFuzzer flags (`-runs=N`, ...) are accepted as well, see [`crate::libfuzzer`].
+use core::time::Duration;
+use std::{fs, net::SocketAddr, path::PathBuf};
+
+use clap::{Parser, ValueEnum};
+use lab_common::dedup;
+use libafl::{schedulers::powersched::PowerSchedule, Error};
+use libafl_bolts::core_affinity::Cores;
+use serde::Deserialize;
+
+use crate::libfuzzer;
+
+/// The power schedules understood by `--schedule`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Schedule {
+    Explore,
+    Exploit,
+    Fast,
+    Coe,
+    Lin,
+    Quad,
+}
+
+impl From<Schedule> for PowerSchedule {
+    fn from(schedule: Schedule) -> Self {
+        match schedule {
+            Schedule::Explore => PowerSchedule::EXPLORE,
+            Schedule::Exploit => PowerSchedule::EXPLOIT,
+            Schedule::Fast => PowerSchedule::FAST,
+            Schedule::Coe => PowerSchedule::COE,
+            Schedule::Lin => PowerSchedule::LIN,
+            Schedule::Quad => PowerSchedule::QUAD,
+        }
+    }
+}
+
+/// Raw command line, every value is optional so that the config file can fill the gaps
+#[derive(Debug, Parser)]
+#[command(
+    name = "fuzzer_libpng",
+    about = "A libfuzzer-like fuzzer for libpng with llmp-multithreading support and restarts"
+)]
+struct Cli {
+    /// TOML file with the campaign settings
+    #[arg(short, long, env = "FUZZ_LIBPNG_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Directories with the initial seeds (can be repeated)
+    #[arg(
+        short,
+        long = "input",
+        env = "FUZZ_LIBPNG_INPUT",
+        value_delimiter = ','
+    )]
+    input: Vec<PathBuf>,
+
+    /// Directory where the evolving corpus is stored
+    #[arg(long, env = "FUZZ_LIBPNG_CORPUS")]
+    corpus: Option<PathBuf>,
+
+    /// Directory where the crashes are stored
+    #[arg(long, env = "FUZZ_LIBPNG_CRASHES")]
+    crashes: Option<PathBuf>,
+
+    /// File name prefix of the crashes, before their hash (libFuzzer `-artifact_prefix`)
+    #[arg(long, env = "FUZZ_LIBPNG_CRASH_PREFIX")]
+    crash_prefix: Option<String>,
+
+    /// Directory where the confirmed timeouts are stored
+    #[arg(long, env = "FUZZ_LIBPNG_HANGS")]
+    hangs: Option<PathBuf>,
+
+    /// Port of the llmp broker
+    #[arg(short = 'p', long, env = "FUZZ_LIBPNG_BROKER_PORT")]
+    broker_port: Option<u16>,
+
+    /// Timeout of a single execution, in milliseconds
+    #[arg(short, long, env = "FUZZ_LIBPNG_TIMEOUT")]
+    timeout: Option<u64>,
+
+    /// Timeout of the re-run that confirms a hang, in milliseconds (default: twice `--timeout`)
+    #[arg(long, env = "FUZZ_LIBPNG_HANG_TIMEOUT")]
+    hang_timeout: Option<u64>,
+
+    /// Number of `fuzz_one` iterations before the client restarts
+    #[arg(long, env = "FUZZ_LIBPNG_ITERATIONS")]
+    iterations: Option<u64>,
+
+    /// Power schedule of the weighted scheduler
+    #[arg(short, long, value_enum, env = "FUZZ_LIBPNG_SCHEDULE")]
+    schedule: Option<Schedule>,
+
+    /// AFL/libFuzzer dictionary files (can be repeated)
+    #[arg(short = 'x', long, env = "FUZZ_LIBPNG_DICT", value_delimiter = ',')]
+    dict: Vec<PathBuf>,
+
+    /// Seed of the random number generator, the clients derive theirs from it (default: random)
+    #[arg(long, env = "FUZZ_LIBPNG_SEED")]
+    seed: Option<u64>,
+
+    /// Make a single-core run with `--seed` reproducible: the corpus in order, no calibration
+    /// or power schedule, no generated or synced inputs, no AFL++ stats
+    #[arg(
+        long,
+        env = "FUZZ_LIBPNG_DETERMINISTIC",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    deterministic: Option<bool>,
+
+    /// Run the CmpLog tracing and I2S stages (needs a `LIBAFL_CMPLOG=1` build)
+    #[arg(
+        long,
+        env = "FUZZ_LIBPNG_CMPLOG",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    cmplog: Option<bool>,
+
+    /// Run the target out of process, in a fresh fork of this binary for every input
+    /// (forkserver build only)
+    #[arg(
+        long,
+        env = "FUZZ_LIBPNG_FORKSERVER",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    forkserver: Option<bool>,
+
+    /// Stop after this many executions of all clients together (libFuzzer `-runs`)
+    #[arg(long, env = "FUZZ_LIBPNG_RUNS")]
+    runs: Option<u64>,
+
+    /// Maximum size of a generated input in bytes (libFuzzer `-max_len`)
+    #[arg(long, env = "FUZZ_LIBPNG_MAX_LEN")]
+    max_len: Option<usize>,
+
+    /// Stop after this many seconds (libFuzzer `-max_total_time`)
+    #[arg(long, env = "FUZZ_LIBPNG_MAX_TOTAL_TIME")]
+    max_total_time: Option<u64>,
+
+    /// Number of fuzzing jobs (libFuzzer `-jobs`), the default for `--workers`
+    #[arg(long, env = "FUZZ_LIBPNG_JOBS")]
+    jobs: Option<usize>,
+
+    /// Number of clients on the cores 0..N (libFuzzer `-workers`), if `--cores` is not given
+    #[arg(long, env = "FUZZ_LIBPNG_WORKERS")]
+    workers: Option<usize>,
+
+    /// Cores to run a client on, e.g. `all`, `0-3` or `0,2,4` (default: 0)
+    #[arg(long, env = "FUZZ_LIBPNG_CORES")]
+    cores: Option<String>,
+
+    /// Only start the clients, attached to the broker already listening on `--broker-port`
+    #[arg(
+        long,
+        env = "FUZZ_LIBPNG_ATTACH",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    attach: Option<bool>,
+
+    /// Connect our broker to the broker of another machine, e.g. `192.168.0.101:1337`
+    #[arg(long, env = "FUZZ_LIBPNG_REMOTE_BROKER")]
+    remote_broker: Option<SocketAddr>,
+
+    /// Write the corpus, crashes, `fuzzer_stats` and `plot_data` of each client to
+    /// `<AFL_OUT>/core<N>/` in the AFL++ layout, instead of `--corpus`/`--crashes`
+    #[arg(long, value_name = "AFL_OUT", env = "FUZZ_LIBPNG_AFL_OUT")]
+    afl_out: Option<PathBuf>,
+
+    /// Directories of other fuzzers to import new inputs from, an AFL++ output directory
+    /// or a plain one (can be repeated)
+    #[arg(
+        long,
+        value_name = "DIR",
+        env = "FUZZ_LIBPNG_SYNC_DIR",
+        value_delimiter = ','
+    )]
+    sync_dir: Vec<PathBuf>,
+
+    /// Show the stats in a terminal UI (stat lines when stdout is not a terminal)
+    #[arg(
+        long,
+        env = "FUZZ_LIBPNG_TUI",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    tui: Option<bool>,
+
+    /// Write the output of each client to `<PREFIX>.<core id>`
+    #[arg(long, value_name = "PREFIX", env = "FUZZ_LIBPNG_STDOUT")]
+    stdout: Option<PathBuf>,
+
+    /// Number of stack frames that tell two crashes apart
+    #[arg(long, env = "FUZZ_LIBPNG_CRASH_DEPTH")]
+    crash_depth: Option<usize>,
+
+    /// Inject a generated PNG every N fuzz_one runs, 0 disables it
+    #[arg(long, env = "FUZZ_LIBPNG_GENERATE_EVERY")]
+    generate_every: Option<u64>,
+
+    /// Minimize the input directories into this directory and exit, like `afl-cmin`
+    #[arg(long, value_name = "OUTPUT_DIR")]
+    cmin: Option<PathBuf>,
+
+    /// Minimize this crash, or all crashes in this directory, to `<crash>.min` and exit
+    #[arg(long, value_name = "CRASH")]
+    tmin: Vec<PathBuf>,
+
+    /// Run the given files and directories (the crashes directory by default) and exit
+    #[arg(long)]
+    replay: bool,
+
+    /// Write lcov/HTML coverage reports of the given corpus directories (default: the corpus)
+    /// to this directory and exit, needs a `LIBAFL_COVERAGE=1` build
+    #[arg(long, value_name = "OUTPUT_DIR")]
+    coverage: Option<PathBuf>,
+
+    /// Also measure this corpus with `--coverage` and report what only one of them reaches
+    #[arg(long, value_name = "CORPUS_DIR", requires = "coverage")]
+    coverage_base: Option<PathBuf>,
+
+    /// Corpus directories (the first one also receives the new inputs) or files to run once
+    #[arg(value_name = "CORPUS_DIR_OR_FILE")]
+    positional: Vec<PathBuf>,
+}
+
+/// The `--config` file, with the same keys as the long command line options
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct FileConfig {
+    input: Vec<PathBuf>,
+    corpus: Option<PathBuf>,
+    crashes: Option<PathBuf>,
+    crash_prefix: Option<String>,
+    hangs: Option<PathBuf>,
+    broker_port: Option<u16>,
+    timeout: Option<u64>,
+    hang_timeout: Option<u64>,
+    iterations: Option<u64>,
+    schedule: Option<Schedule>,
+    dict: Vec<PathBuf>,
+    seed: Option<u64>,
+    deterministic: Option<bool>,
+    cmplog: Option<bool>,
+    forkserver: Option<bool>,
+    runs: Option<u64>,
+    max_len: Option<usize>,
+    max_total_time: Option<u64>,
+    jobs: Option<usize>,
+    workers: Option<usize>,
+    cores: Option<String>,
+    attach: Option<bool>,
+    remote_broker: Option<SocketAddr>,
+    afl_out: Option<PathBuf>,
+    sync_dir: Vec<PathBuf>,
+    tui: Option<bool>,
+    stdout: Option<PathBuf>,
+    crash_depth: Option<usize>,
+    generate_every: Option<u64>,
+}
+
+impl FileConfig {
+    fn load(path: &PathBuf) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|err| {
+            Error::illegal_argument(format!("Invalid config file {}: {err}", path.display()))
+        })
+    }
+}
+
+/// The resolved settings of a campaign
+#[derive(Debug, Clone)]
+pub struct FuzzerOptions {
+    pub input: Vec<PathBuf>,
+    pub corpus: PathBuf,
+    pub crashes: PathBuf,
+    pub crash_prefix: Option<String>,
+    pub hangs: PathBuf,
+    pub broker_port: u16,
+    pub timeout: Duration,
+    pub hang_timeout: Duration,
+    pub iterations: u64,
+    pub schedule: Schedule,
+    pub dict: Vec<PathBuf>,
+    pub seed: Option<u64>,
+    pub deterministic: bool,
+    pub cmplog: bool,
+    pub forkserver: bool,
+    pub runs: Option<u64>,
+    pub max_len: Option<usize>,
+    pub max_total_time: Option<Duration>,
+    pub cores: Cores,
+    pub attach: bool,
+    pub remote_broker: Option<SocketAddr>,
+    pub afl_out: Option<PathBuf>,
+    pub sync_dirs: Vec<PathBuf>,
+    pub tui: bool,
+    pub stdout: Option<PathBuf>,
+    pub crash_depth: usize,
+    pub generate_every: u64,
+    /// Single files to run once instead of fuzzing
+    pub files: Vec<PathBuf>,
+    /// Output directory of the corpus minimization
+    pub cmin: Option<PathBuf>,
+    /// Crashes, or directories of crashes, to minimize
+    pub tmin: Vec<PathBuf>,
+    /// Files and directories to replay
+    pub replay: Vec<PathBuf>,
+    /// Output directory of the coverage reports
+    pub coverage: Option<PathBuf>,
+    /// Corpus to compare the coverage with
+    pub coverage_base: Option<PathBuf>,
+    /// Corpus directories to measure the coverage of
+    pub coverage_corpus: Vec<PathBuf>,
+}
+
+impl FuzzerOptions {
+    /// Parse the command line, the environment and the optional config file
+    pub fn parse(args: &[String]) -> Result<Self, Error> {
+        let cli = Cli::parse_from(libfuzzer::translate_args(args));
+        let file = match &cli.config {
+            Some(path) => FileConfig::load(path)?,
+            None => FileConfig::default(),
+        };
+
+        let replay = match (cli.replay, cli.positional.is_empty()) {
+            (false, _) => Vec::new(),
+            (true, false) => cli.positional.clone(),
+            (true, true) => vec![cli
+                .crashes
+                .clone()
+                .or(file.crashes.clone())
+                .unwrap_or_else(|| PathBuf::from("./crashes"))],
+        };
+
+        // Like libFuzzer: only files are run once, else they are all corpus directories
+        let (files, dirs) =
+            if !cli.positional.is_empty() && cli.positional.iter().all(|p| p.is_file()) {
+                (cli.positional, Vec::new())
+            } else {
+                (Vec::new(), cli.positional)
+            };
+        let cli_input = if cli.input.is_empty() {
+            dirs.clone()
+        } else {
+            cli.input
+        };
+        // `--workers`/`--jobs` clients on the cores 0..N, unless the cores are given
+        let clients = cli.workers.or(file.workers).or(cli.jobs).or(file.jobs);
+        let cores = match (cli.cores.or(file.cores), clients) {
+            (Some(cores), _) => cores,
+            (None, Some(clients)) => format!("0-{}", clients.max(1) - 1),
+            (None, None) => "0".to_string(),
+        };
+
+        let corpus = cli
+            .corpus
+            .or(dirs.first().cloned())
+            .or(file.corpus)
+            .unwrap_or_else(|| PathBuf::from("./corpus"));
+        let coverage_corpus = if dirs.is_empty() {
+            vec![corpus.clone()]
+        } else {
+            dirs
+        };
+
+        let timeout = Duration::from_millis(cli.timeout.or(file.timeout).unwrap_or(10_000));
+
+        let seed = cli.seed.or(file.seed);
+        let deterministic = cli.deterministic.or(file.deterministic).unwrap_or_default();
+        let cores = Cores::from_cmdline(&cores)?;
+        if deterministic && (seed.is_none() || cores.ids.len() != 1) {
+            return Err(Error::illegal_argument(
+                "--deterministic needs a --seed and a single core",
+            ));
+        }
+
+        Ok(Self {
+            input: pick_vec(cli_input, file.input, || vec![PathBuf::from("./seeds-png")]),
+            corpus,
+            crashes: cli
+                .crashes
+                .or(file.crashes)
+                .unwrap_or_else(|| PathBuf::from("./crashes")),
+            crash_prefix: cli.crash_prefix.or(file.crash_prefix),
+            hangs: cli
+                .hangs
+                .or(file.hangs)
+                .unwrap_or_else(|| PathBuf::from("./hangs")),
+            broker_port: cli.broker_port.or(file.broker_port).unwrap_or(1337),
+            timeout,
+            hang_timeout: cli
+                .hang_timeout
+                .or(file.hang_timeout)
+                .map_or(timeout * 2, Duration::from_millis),
+            iterations: cli.iterations.or(file.iterations).unwrap_or(1_000_000),
+            schedule: cli.schedule.or(file.schedule).unwrap_or(Schedule::Fast),
+            dict: pick_vec(cli.dict, file.dict, Vec::new),
+            seed,
+            deterministic,
+            cmplog: cli.cmplog.or(file.cmplog).unwrap_or_default(),
+            forkserver: cli.forkserver.or(file.forkserver).unwrap_or_default(),
+            runs: cli.runs.or(file.runs),
+            max_len: cli.max_len.or(file.max_len),
+            max_total_time: cli
+                .max_total_time
+                .or(file.max_total_time)
+                .map(Duration::from_secs),
+            cores,
+            attach: cli.attach.or(file.attach).unwrap_or_default(),
+            remote_broker: cli.remote_broker.or(file.remote_broker),
+            afl_out: cli.afl_out.or(file.afl_out),
+            sync_dirs: pick_vec(cli.sync_dir, file.sync_dir, Vec::new),
+            tui: cli.tui.or(file.tui).unwrap_or_default(),
+            stdout: cli.stdout.or(file.stdout),
+            crash_depth: cli
+                .crash_depth
+                .or(file.crash_depth)
+                .unwrap_or(dedup::DEFAULT_DEPTH),
+            generate_every: cli.generate_every.or(file.generate_every).unwrap_or(10_000),
+            files,
+            cmin: cli.cmin,
+            tmin: cli.tmin,
+            replay,
+            coverage: cli.coverage,
+            coverage_base: cli.coverage_base,
+            coverage_corpus,
+        })
+    }
+}
+
+/// The command line wins over the config file as soon as it names at least one value
+fn pick_vec<T>(cli: Vec<T>, file: Vec<T>, default: impl FnOnce() -> Vec<T>) -> Vec<T> {
+    if !cli.is_empty() {
+        cli
+    } else if !file.is_empty() {
+        file
+    } else {
+        default()
+    }
+}
@@ -0,0 +1,511 @@
+//! PNG-aware mutations.
+//!
+//! Inputs are parsed into chunks, mutated on the chunk level and written back with
+//! the right lengths and CRCs, so libpng gets past the chunk parsing instead of
+//! bailing out on the first broken CRC. Inputs that are not PNG files are skipped.
+use std::borrow::Cow;
+
+use libafl::{
+    corpus::Corpus,
+    inputs::{BytesInput, HasMutatorBytes, UsesInput},
+    mutators::{MutationResult, Mutator},
+    random_corpus_id,
+    state::{HasCorpus, HasMaxSize, HasRand},
+    Error,
+};
+use libafl_bolts::{
+    rands::Rand,
+    tuples::{tuple_list, tuple_list_type},
+    Named,
+};
+
+/// The 8 bytes every PNG file starts with
+pub const SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+/// Chunk types libpng knows about, with a typical payload length
+pub const CHUNK_TYPES: &[(&[u8; 4], usize)] = &[
+    (b"IHDR", 13),
+    (b"PLTE", 48),
+    (b"IDAT", 64),
+    (b"IEND", 0),
+    (b"tRNS", 16),
+    (b"gAMA", 4),
+    (b"cHRM", 32),
+    (b"sRGB", 1),
+    (b"iCCP", 32),
+    (b"sBIT", 4),
+    (b"bKGD", 6),
+    (b"hIST", 32),
+    (b"pHYs", 9),
+    (b"sPLT", 32),
+    (b"tIME", 7),
+    (b"tEXt", 16),
+    (b"zTXt", 16),
+    (b"iTXt", 16),
+    (b"oFFs", 9),
+    (b"pCAL", 32),
+    (b"sCAL", 8),
+    (b"eXIf", 16),
+    (b"acTL", 8),
+    (b"fcTL", 26),
+    (b"fdAT", 64),
+];
+
+/// Values that make the 32-bit fields (width, height, offsets) interesting
+const INTERESTING_32: [u32; 8] = [0, 1, 0x7f, 0xff, 0x7fff, 0xffff, 0x7fff_ffff, 0xffff_ffff];
+
+/// One chunk, the length and CRC are computed when it is written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub kind: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn new(kind: [u8; 4], data: Vec<u8>) -> Self {
+        Self { kind, data }
+    }
+}
+
+/// A PNG file as a list of chunks, plus whatever follows the last complete chunk
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Png {
+    pub chunks: Vec<Chunk>,
+    pub trailer: Vec<u8>,
+}
+
+impl Png {
+    /// Split `bytes` into chunks, `None` if it does not start with the PNG signature
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut rest = bytes.strip_prefix(&SIGNATURE)?;
+        let mut chunks = Vec::new();
+        while rest.len() >= 12 {
+            let len = u32::from_be_bytes(rest[0..4].try_into().unwrap()) as usize;
+            if len > rest.len() - 12 {
+                break;
+            }
+            let kind = rest[4..8].try_into().unwrap();
+            chunks.push(Chunk::new(kind, rest[8..8 + len].to_vec()));
+            rest = &rest[12 + len..];
+        }
+        Some(Self {
+            chunks,
+            trailer: rest.to_vec(),
+        })
+    }
+
+    /// The file, with the lengths and CRCs of all chunks recomputed
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let len = self.chunks.iter().map(|c| c.data.len() + 12).sum::<usize>();
+        let mut bytes = Vec::with_capacity(SIGNATURE.len() + len + self.trailer.len());
+        bytes.extend_from_slice(&SIGNATURE);
+        for chunk in &self.chunks {
+            bytes.extend_from_slice(&(chunk.data.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&chunk.kind);
+            bytes.extend_from_slice(&chunk.data);
+            let crc = crc32(&[&chunk.kind[..], &chunk.data].concat());
+            bytes.extend_from_slice(&crc.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.trailer);
+        bytes
+    }
+}
+
+/// The CRC-32 of the PNG specification (ISO 3309)
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Random payload bytes
+pub fn random_data<R: Rand>(rand: &mut R, len: usize) -> Vec<u8> {
+    (0..len).map(|_| rand.next() as u8).collect()
+}
+
+/// Parse the input, apply `mutate` to it and write it back with fixed lengths and CRCs
+fn mutate_png<S, F>(
+    state: &mut S,
+    input: &mut BytesInput,
+    mutate: F,
+) -> Result<MutationResult, Error>
+where
+    S: HasRand + HasMaxSize,
+    F: FnOnce(&mut S, &mut Png) -> MutationResult,
+{
+    let Some(mut png) = Png::parse(input.bytes()) else {
+        return Ok(MutationResult::Skipped);
+    };
+    if mutate(state, &mut png) == MutationResult::Skipped {
+        return Ok(MutationResult::Skipped);
+    }
+    let bytes = png.to_bytes();
+    if bytes.len() > state.max_size() {
+        return Ok(MutationResult::Skipped);
+    }
+    *input = BytesInput::new(bytes);
+    Ok(MutationResult::Mutated)
+}
+
+/// Insert a new chunk of a known type with a random payload
+#[derive(Debug, Default)]
+pub struct PngChunkInsertMutator;
+
+impl<S> Mutator<BytesInput, S> for PngChunkInsertMutator
+where
+    S: HasRand + HasMaxSize,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut BytesInput) -> Result<MutationResult, Error> {
+        mutate_png(state, input, |state, png| {
+            let rand = state.rand_mut();
+            let (kind, len) = CHUNK_TYPES[rand.below(CHUNK_TYPES.len())];
+            let data_len = rand.below(2 * len + 1);
+            let data = random_data(rand, data_len);
+            let pos = rand.below(png.chunks.len() + 1);
+            png.chunks.insert(pos, Chunk::new(*kind, data));
+            MutationResult::Mutated
+        })
+    }
+}
+
+impl Named for PngChunkInsertMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        static NAME: Cow<'static, str> = Cow::Borrowed("PngChunkInsertMutator");
+        &NAME
+    }
+}
+
+/// Remove a chunk
+#[derive(Debug, Default)]
+pub struct PngChunkDeleteMutator;
+
+impl<S> Mutator<BytesInput, S> for PngChunkDeleteMutator
+where
+    S: HasRand + HasMaxSize,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut BytesInput) -> Result<MutationResult, Error> {
+        mutate_png(state, input, |state, png| {
+            if png.chunks.is_empty() {
+                return MutationResult::Skipped;
+            }
+            let pos = state.rand_mut().below(png.chunks.len());
+            png.chunks.remove(pos);
+            MutationResult::Mutated
+        })
+    }
+}
+
+impl Named for PngChunkDeleteMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        static NAME: Cow<'static, str> = Cow::Borrowed("PngChunkDeleteMutator");
+        &NAME
+    }
+}
+
+/// Repeat a chunk right after itself, e.g. a second IHDR or PLTE
+#[derive(Debug, Default)]
+pub struct PngChunkDuplicateMutator;
+
+impl<S> Mutator<BytesInput, S> for PngChunkDuplicateMutator
+where
+    S: HasRand + HasMaxSize,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut BytesInput) -> Result<MutationResult, Error> {
+        mutate_png(state, input, |state, png| {
+            if png.chunks.is_empty() {
+                return MutationResult::Skipped;
+            }
+            let pos = state.rand_mut().below(png.chunks.len());
+            let chunk = png.chunks[pos].clone();
+            png.chunks.insert(pos + 1, chunk);
+            MutationResult::Mutated
+        })
+    }
+}
+
+impl Named for PngChunkDuplicateMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        static NAME: Cow<'static, str> = Cow::Borrowed("PngChunkDuplicateMutator");
+        &NAME
+    }
+}
+
+/// Swap two chunks, so they show up in an order libpng does not expect
+#[derive(Debug, Default)]
+pub struct PngChunkSwapMutator;
+
+impl<S> Mutator<BytesInput, S> for PngChunkSwapMutator
+where
+    S: HasRand + HasMaxSize,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut BytesInput) -> Result<MutationResult, Error> {
+        mutate_png(state, input, |state, png| {
+            if png.chunks.len() < 2 {
+                return MutationResult::Skipped;
+            }
+            let rand = state.rand_mut();
+            let first = rand.below(png.chunks.len());
+            let second = rand.below(png.chunks.len());
+            if first == second {
+                return MutationResult::Skipped;
+            }
+            png.chunks.swap(first, second);
+            MutationResult::Mutated
+        })
+    }
+}
+
+impl Named for PngChunkSwapMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        static NAME: Cow<'static, str> = Cow::Borrowed("PngChunkSwapMutator");
+        &NAME
+    }
+}
+
+/// Grow a chunk payload with random bytes or cut it short
+#[derive(Debug, Default)]
+pub struct PngChunkResizeMutator;
+
+impl<S> Mutator<BytesInput, S> for PngChunkResizeMutator
+where
+    S: HasRand + HasMaxSize,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut BytesInput) -> Result<MutationResult, Error> {
+        mutate_png(state, input, |state, png| {
+            if png.chunks.is_empty() {
+                return MutationResult::Skipped;
+            }
+            let rand = state.rand_mut();
+            let chunk = &mut png.chunks[rand.below(png.chunks.len())];
+            let len = chunk.data.len();
+            if len > 0 && rand.coinflip(0.5) {
+                chunk.data.truncate(rand.below(len));
+            } else {
+                let extra = 1 + rand.below(len.max(16));
+                chunk.data.extend(random_data(rand, extra));
+            }
+            MutationResult::Mutated
+        })
+    }
+}
+
+impl Named for PngChunkResizeMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        static NAME: Cow<'static, str> = Cow::Borrowed("PngChunkResizeMutator");
+        &NAME
+    }
+}
+
+/// Mutate the payload of a chunk: a bit, a byte or an interesting 32-bit big-endian field
+#[derive(Debug, Default)]
+pub struct PngChunkDataMutator;
+
+impl<S> Mutator<BytesInput, S> for PngChunkDataMutator
+where
+    S: HasRand + HasMaxSize,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut BytesInput) -> Result<MutationResult, Error> {
+        mutate_png(state, input, |state, png| {
+            if png.chunks.iter().all(|chunk| chunk.data.is_empty()) {
+                return MutationResult::Skipped;
+            }
+            let rand = state.rand_mut();
+            let chunk = loop {
+                let pos = rand.below(png.chunks.len());
+                if !png.chunks[pos].data.is_empty() {
+                    break &mut png.chunks[pos];
+                }
+            };
+
+            let len = chunk.data.len();
+            match rand.below(3) {
+                0 => chunk.data[rand.below(len)] ^= 1 << rand.below(8),
+                1 => chunk.data[rand.below(len)] = rand.next() as u8,
+                _ if len >= 4 => {
+                    // Fields are 4-byte aligned in IHDR, pHYs, oFFs, acTL and fcTL
+                    let pos = rand.below(len / 4) * 4;
+                    let value = INTERESTING_32[rand.below(INTERESTING_32.len())];
+                    chunk.data[pos..pos + 4].copy_from_slice(&value.to_be_bytes());
+                }
+                _ => chunk.data[rand.below(len)] = [0, 0x7f, 0x80, 0xff][rand.below(4)],
+            }
+            MutationResult::Mutated
+        })
+    }
+}
+
+impl Named for PngChunkDataMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        static NAME: Cow<'static, str> = Cow::Borrowed("PngChunkDataMutator");
+        &NAME
+    }
+}
+
+/// Insert a chunk taken from another input of the corpus
+#[derive(Debug, Default)]
+pub struct PngChunkSpliceMutator;
+
+impl<S> Mutator<BytesInput, S> for PngChunkSpliceMutator
+where
+    S: HasRand + HasMaxSize + HasCorpus + UsesInput<Input = BytesInput>,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut BytesInput) -> Result<MutationResult, Error> {
+        let id = random_corpus_id!(state.corpus(), state.rand_mut());
+        let other = {
+            let mut testcase = state.corpus().get(id)?.borrow_mut();
+            Png::parse(testcase.load_input(state.corpus())?.bytes())
+        };
+        let Some(other) = other.filter(|other| !other.chunks.is_empty()) else {
+            return Ok(MutationResult::Skipped);
+        };
+
+        mutate_png(state, input, |state, png| {
+            let rand = state.rand_mut();
+            let chunk = other.chunks[rand.below(other.chunks.len())].clone();
+            let pos = rand.below(png.chunks.len() + 1);
+            png.chunks.insert(pos, chunk);
+            MutationResult::Mutated
+        })
+    }
+}
+
+impl Named for PngChunkSpliceMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        static NAME: Cow<'static, str> = Cow::Borrowed("PngChunkSpliceMutator");
+        &NAME
+    }
+}
+
+/// Only write the input back, fixing the lengths and CRCs broken by the havoc mutations
+#[derive(Debug, Default)]
+pub struct PngCrcFixMutator;
+
+impl<S> Mutator<BytesInput, S> for PngCrcFixMutator
+where
+    S: HasRand + HasMaxSize,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut BytesInput) -> Result<MutationResult, Error> {
+        let original = input.bytes().to_vec();
+        let result = mutate_png(state, input, |_state, _png| MutationResult::Mutated)?;
+        if result == MutationResult::Mutated && input.bytes() == original.as_slice() {
+            return Ok(MutationResult::Skipped);
+        }
+        Ok(result)
+    }
+}
+
+impl Named for PngCrcFixMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        static NAME: Cow<'static, str> = Cow::Borrowed("PngCrcFixMutator");
+        &NAME
+    }
+}
+
+/// The chunk mutations, to be merged with `havoc_mutations()`
+pub type PngMutationsType = tuple_list_type!(
+    PngChunkInsertMutator,
+    PngChunkDeleteMutator,
+    PngChunkDuplicateMutator,
+    PngChunkSwapMutator,
+    PngChunkResizeMutator,
+    PngChunkDataMutator,
+    PngChunkSpliceMutator,
+    PngCrcFixMutator
+);
+
+/// Get the chunk mutations
+pub fn png_mutations() -> PngMutationsType {
+    tuple_list!(
+        PngChunkInsertMutator,
+        PngChunkDeleteMutator,
+        PngChunkDuplicateMutator,
+        PngChunkSwapMutator,
+        PngChunkResizeMutator,
+        PngChunkDataMutator,
+        PngChunkSpliceMutator,
+        PngCrcFixMutator
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use libafl::{
+        inputs::{BytesInput, HasMutatorBytes},
+        mutators::{MutationResult, Mutator},
+        state::NopState,
+    };
+
+    use super::{crc32, Chunk, Png, PngCrcFixMutator, SIGNATURE};
+
+    /// A 1x1 grayscale image: IHDR, an empty IDAT and IEND
+    fn png() -> Png {
+        Png {
+            chunks: vec![
+                Chunk::new(*b"IHDR", vec![0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]),
+                Chunk::new(*b"IDAT", vec![]),
+                Chunk::new(*b"IEND", vec![]),
+            ],
+            trailer: vec![],
+        }
+    }
+
+    #[test]
+    fn crc_of_iend() {
+        // Every PNG file ends in 00 00 00 00 IEND AE 42 60 82
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(png().to_bytes()[..8], SIGNATURE);
+        assert!(png().to_bytes().ends_with(b"\0\0\0\0IEND\xae\x42\x60\x82"));
+    }
+
+    #[test]
+    fn parse_to_bytes_round_trip() {
+        let bytes = png().to_bytes();
+        assert_eq!(Png::parse(&bytes), Some(png()));
+        assert_eq!(Png::parse(&bytes).unwrap().to_bytes(), bytes);
+    }
+
+    #[test]
+    fn truncated_chunk_is_the_trailer() {
+        let mut bytes = png().to_bytes();
+        bytes.extend_from_slice(&[0, 0, 0, 9, b't', b'E', b'X', b't', b'a']);
+        let parsed = Png::parse(&bytes).unwrap();
+        assert_eq!(parsed.chunks, png().chunks);
+        assert_eq!(parsed.trailer, [0, 0, 0, 9, b't', b'E', b'X', b't', b'a']);
+        assert_eq!(parsed.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn not_a_png() {
+        assert_eq!(Png::parse(b"GIF89a"), None);
+        assert_eq!(Png::parse(&SIGNATURE[..7]), None);
+    }
+
+    #[test]
+    fn crc_fix() {
+        let fixed = png().to_bytes();
+        let mut broken = fixed.clone();
+        // CRC of IHDR, after signature, length, type and 13 bytes of data
+        broken[8 + 8 + 13] ^= 0xff;
+        let mut state = NopState::<BytesInput>::new();
+        let mut input = BytesInput::new(broken);
+        assert_eq!(
+            PngCrcFixMutator.mutate(&mut state, &mut input).unwrap(),
+            MutationResult::Mutated
+        );
+        assert_eq!(input.bytes(), fixed.as_slice());
+        // nothing left to fix
+        assert_eq!(
+            PngCrcFixMutator.mutate(&mut state, &mut input).unwrap(),
+            MutationResult::Skipped
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! Dictionaries for the token mutations.
+//!
+//! The built-in PNG tokens are merged with the user supplied AFL/libFuzzer
+//! dictionaries and the result is persisted next to the output directory of the campaign
+//! (`./corpus.dict`, or `<AFL_OUT>.dict` with `--afl-out`) by its first client, so restarts
+//! and other targets can load it again with `--dict`.
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use libafl::{mutators::Tokens, Error};
+#[cfg(any(target_os = "linux", target_vendor = "apple"))]
+use libafl_targets::autotokens;
+
+/// The tokens every PNG file is made of
+pub fn png_tokens() -> Tokens {
+    Tokens::from([
+        vec![137, 80, 78, 71, 13, 10, 26, 10], // PNG header
+        "IHDR".as_bytes().to_vec(),
+        "IDAT".as_bytes().to_vec(),
+        "PLTE".as_bytes().to_vec(),
+        "IEND".as_bytes().to_vec(),
+    ])
+}
+
+/// The tokens the `LIBAFL_AUTOTOKENS=1` build embedded in the target binary
+#[cfg(any(target_os = "linux", target_vendor = "apple"))]
+pub fn target_tokens() -> Result<Tokens, Error> {
+    autotokens()
+}
+
+/// The token section is only read back on Linux and Apple targets
+#[cfg(not(any(target_os = "linux", target_vendor = "apple")))]
+pub fn target_tokens() -> Result<Tokens, Error> {
+    Ok(Tokens::new())
+}
+
+/// The merged dictionary kept next to the corpus directory, or the AFL++ output directory
+pub fn persisted_dict_path(output_dir: &Path) -> PathBuf {
+    // Appended to the whole name, `out.v2` is not an extension
+    let mut name = output_dir.file_name().unwrap_or_default().to_os_string();
+    name.push(".dict");
+    output_dir.with_file_name(name)
+}
+
+/// Add the tokens of AFL-style dictionary files.
+///
+/// Both plain `"value"` and `name="value"` lines are accepted, with `\xNN`
+/// escapes, empty lines and `#` comments.
+pub fn load_dicts(tokens: &mut Tokens, dicts: &[PathBuf]) -> Result<(), Error> {
+    for dict in dicts {
+        tokens.add_from_file(dict).map_err(|err| {
+            Error::illegal_argument(format!(
+                "Failed to load dictionary {}: {err}",
+                dict.display()
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+/// Write the tokens as an AFL-style dictionary
+pub fn write_dict(tokens: &Tokens, path: &Path) -> Result<(), Error> {
+    let mut dict = String::new();
+    for (i, token) in tokens.tokens().iter().enumerate() {
+        writeln!(dict, "token_{i}=\"{}\"", escape(token)).unwrap();
+    }
+
+    // A restarting client may read it meanwhile, only ever rename a complete one over it
+    let tmp = path.with_extension(format!("dict.{}.tmp", process::id()));
+    fs::write(&tmp, dict)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Escape a token the way `Tokens::add_from_file` decodes it
+fn escape(token: &[u8]) -> String {
+    let mut escaped = String::with_capacity(token.len());
+    for &byte in token {
+        match byte {
+            b'"' | b'\\' => {
+                escaped.push('\\');
+                escaped.push(byte as char);
+            }
+            0x20..=0x7e => escaped.push(byte as char),
+            _ => write!(escaped, "\\x{byte:02x}").unwrap(),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path, process};
+
+    use libafl::mutators::Tokens;
+
+    use super::{escape, persisted_dict_path, png_tokens, write_dict};
+
+    #[test]
+    fn escaped_bytes() {
+        assert_eq!(escape(b"IHDR"), "IHDR");
+        assert_eq!(escape(b"a\"b\\c"), "a\\\"b\\\\c");
+        assert_eq!(escape(&[0, 0x7f, 0x89, b' ']), "\\x00\\x7f\\x89 ");
+    }
+
+    #[test]
+    fn write_dict_round_trip() {
+        let mut tokens = png_tokens();
+        tokens.add_tokens(&[
+            b"quote\"and\\backslash".to_vec(),
+            vec![0, 1, 0xff, b'#', b'='],
+        ]);
+        let dir = env::temp_dir().join(format!("tokens-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("corpus.dict");
+        write_dict(&tokens, &path).unwrap();
+
+        let mut read = Tokens::new();
+        read.add_from_file(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(read.tokens(), tokens.tokens());
+    }
+
+    #[test]
+    fn dict_next_to_the_output_dir() {
+        assert_eq!(
+            persisted_dict_path(Path::new("./corpus")),
+            Path::new("./corpus.dict")
+        );
+        assert_eq!(
+            persisted_dict_path(Path::new("/tmp/afl-out")),
+            Path::new("/tmp/afl-out.dict")
+        );
+        assert_eq!(
+            persisted_dict_path(Path::new("out.v2/")),
+            Path::new("out.v2.dict")
+        );
+    }
+}